@@ -12,12 +12,13 @@ fn hello(_req: Request, rsp: &mut Response) {
 fn main() {
     may::config().set_workers(1).set_stack_size(0x10000);
     env_logger::init();
-    // config the timeout would hurt the performance here
+    // the socket read/write timeouts would hurt the performance here,
+    // the keep-alive and header timeouts are checked by a timer instead
     let mut server = HttpServer::new(hello);
     server
         .set_server_name("may_http".to_owned())
-        .set_read_timeout(Some(Duration::from_secs(10)))
-        .set_write_timeout(Some(Duration::from_secs(10)));
+        .set_keep_alive_timeout(Some(Duration::from_secs(10)))
+        .set_header_read_timeout(Some(Duration::from_secs(10)));
 
     let server = server.start("127.0.0.1:8080").unwrap();
     server.wait();
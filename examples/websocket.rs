@@ -0,0 +1,29 @@
+use may_http::server::*;
+use may_http::websocket::{self, Message, WebSocket};
+
+// test with a websocket client, e.g. `websocat ws://127.0.0.1:8080/`
+fn echo(req: Request, rsp: &mut Response) {
+    if !websocket::is_upgrade(&req) {
+        rsp.send(b"please connect with a websocket client").unwrap();
+        return;
+    }
+
+    let mut ws = match WebSocket::accept(req, rsp) {
+        Ok(ws) => ws,
+        Err(e) => return eprintln!("websocket handshake failed, err = {}", e),
+    };
+    while let Ok(msg) = ws.read_message() {
+        match msg {
+            Message::Text(_) | Message::Binary(_) => ws.write_message(msg).unwrap(),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+}
+
+fn main() {
+    may::config().set_workers(1).set_stack_size(0x10000);
+    env_logger::init();
+    let server = HttpServer::new(echo).start("127.0.0.1:8080").unwrap();
+    server.wait();
+}
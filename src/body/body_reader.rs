@@ -1,21 +1,124 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::fmt;
 use std::io::{self, Read};
+use std::mem;
 use std::rc::Rc;
+use std::str;
+
+use http::header::*;
 
 use self::BodyReader::*;
+use crate::body::coding::Coding;
+use crate::Error;
 
 pub enum BodyReader {
     SizedReader(Rc<RefCell<dyn Read>>, usize),
-    ChunkReader(Rc<RefCell<dyn Read>>, Option<usize>),
+    /// the chunk extensions and the trailer fields are stored in the info
+    ChunkReader(Rc<RefCell<dyn Read>>, Option<usize>, Rc<RefCell<ChunkInfo>>),
+    /// the body is delimited by the connection close, the flag is set at eof
+    CloseReader(Rc<RefCell<dyn Read>>, bool),
+    /// the content decoder and the raw body reader that it wraps
+    DecodeReader(Box<dyn Read>, Rc<RefCell<BodyReader>>),
+    LimitReader(Box<BodyReader>, BodyLimit),
     EmptyReader,
 }
 
+// the `name[=value]` chunk extensions
+type Extensions = Vec<(String, Option<String>)>;
+
+/// the extensions and the trailers of the chunked body
+#[derive(Debug, Default)]
+pub struct ChunkInfo {
+    extensions: Extensions,
+    trailers: HeaderMap,
+}
+
+impl ChunkInfo {
+    /// the `name[=value]` extensions of the last read chunk
+    pub fn extensions(&self) -> &[(String, Option<String>)] {
+        &self.extensions
+    }
+
+    /// the trailer fields, it's empty until the body is read to the end
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+}
+
+// the raw body that shared with the decoder
+struct RawBody(Rc<RefCell<BodyReader>>);
+
+impl Read for RawBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+/// the body size limit of the `LimitReader`
+#[derive(Debug)]
+pub struct BodyLimit {
+    max: usize,
+    read: usize,
+    // shared with the server to answer the `413 Payload Too Large`
+    exceeded: Rc<Cell<bool>>,
+}
+
+impl BodyReader {
+    // set the body size limit, `None` means unlimited
+    pub(crate) fn limit(&mut self, max: Option<usize>, exceeded: Rc<Cell<bool>>) {
+        let max = max.unwrap_or(usize::MAX);
+        match *self {
+            EmptyReader => {}
+            LimitReader(_, ref mut limit) => {
+                limit.max = max;
+                // the lowered limit may be exceeded by the body already read
+                if limit.read > max {
+                    limit.exceeded.set(true);
+                }
+            }
+            _ => {
+                let inner = mem::replace(self, EmptyReader);
+                let limit = BodyLimit {
+                    max,
+                    read: 0,
+                    exceeded,
+                };
+                *self = LimitReader(Box::new(inner), limit);
+            }
+        }
+    }
+
+    // decode the body with the codings in the order they are applied
+    pub(crate) fn decode(&mut self, codings: &[Coding]) {
+        let raw = Rc::new(RefCell::new(mem::replace(self, EmptyReader)));
+        let mut reader: Box<dyn Read> = Box::new(RawBody(raw.clone()));
+        for coding in codings.iter().rev() {
+            reader = coding.decoder(reader);
+        }
+        *self = DecodeReader(reader, raw);
+    }
+
+    // stop reading, so that the remaining body is not drained on drop
+    fn abort(&mut self) {
+        match *self {
+            SizedReader(_, ref mut remain) => *remain = 0,
+            ChunkReader(_, ref mut remain, _) => *remain = Some(0),
+            CloseReader(_, ref mut eof) => *eof = true,
+            DecodeReader(_, ref raw) => raw.borrow_mut().abort(),
+            LimitReader(ref mut inner, _) => inner.abort(),
+            EmptyReader => {}
+        }
+    }
+}
+
 impl fmt::Debug for BodyReader {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         let name = match *self {
             SizedReader(..) => "SizedReader",
             ChunkReader(..) => "ChunkReader",
+            CloseReader(..) => "CloseReader",
+            DecodeReader(..) => "DecodeReader",
+            LimitReader(..) => "LimitReader",
             EmptyReader => "EmptyReader",
         };
         write!(f, "BodyReader {}", name)
@@ -34,21 +137,29 @@ impl Read for BodyReader {
                 }
                 let mut r = r.borrow_mut();
                 let n = r.read(&mut buf[0..len])?;
+                if n == 0 {
+                    *remain = 0;
+                    return Err(Error::ConnectionClosed.into());
+                }
                 *remain -= n;
                 Ok(n)
             }
-            ChunkReader(ref r, ref mut opt_remaining) => {
+            ChunkReader(ref r, ref mut opt_remaining, ref info) => {
                 let mut r = r.borrow_mut();
                 let mut rem = match *opt_remaining {
                     Some(ref rem) => *rem,
                     // None means we don't know the size of the next chunk
-                    None => read_chunk_size(&mut *r)?,
+                    None => {
+                        let (size, extensions) = read_chunk_size(&mut *r)?;
+                        info.borrow_mut().extensions = extensions;
+                        size
+                    }
                 };
                 trace!("Chunked read, remaining={:?}", rem);
 
                 if rem == 0 {
                     if opt_remaining.is_none() {
-                        eat(&mut *r, b"\r\n")?;
+                        read_trailers(&mut *r, &mut info.borrow_mut().trailers)?;
                     }
 
                     *opt_remaining = Some(0);
@@ -66,7 +177,7 @@ impl Read for BodyReader {
 
                 if count == 0 {
                     *opt_remaining = Some(0);
-                    return Err(io::Error::new(io::ErrorKind::Other, "early eof"));
+                    return Err(Error::ConnectionClosed.into());
                 }
 
                 rem -= count;
@@ -78,6 +189,39 @@ impl Read for BodyReader {
                 };
                 Ok(count)
             }
+            CloseReader(ref r, ref mut eof) => {
+                if *eof || buf.is_empty() {
+                    return Ok(0);
+                }
+                let n = r.borrow_mut().read(buf)?;
+                *eof = n == 0;
+                Ok(n)
+            }
+            DecodeReader(ref mut r, _) => r.read(buf),
+            LimitReader(ref mut inner, ref mut limit) => {
+                let too_large = || io::Error::from(Error::BodyTooLarge);
+                if limit.exceeded.get() {
+                    return Err(too_large());
+                }
+                let allow = limit.max.saturating_sub(limit.read);
+                // reject the declared size without reading anything
+                let declared = match **inner {
+                    SizedReader(_, remain) => remain > allow,
+                    _ => false,
+                };
+                if !declared {
+                    // read one more byte to detect the overflow
+                    let len = cmp::min(buf.len(), allow.saturating_add(1));
+                    let n = inner.read(&mut buf[..len])?;
+                    if n <= allow {
+                        limit.read += n;
+                        return Ok(n);
+                    }
+                }
+                limit.exceeded.set(true);
+                inner.abort();
+                Err(too_large())
+            }
             EmptyReader => Ok(0),
         }
     }
@@ -85,6 +229,11 @@ impl Read for BodyReader {
 
 impl Drop for BodyReader {
     fn drop(&mut self) {
+        // the connection is closed after all, don't wait for that
+        // and the decoder leaves the raw body to the inner reader
+        if let CloseReader(..) | DecodeReader(..) = *self {
+            return;
+        }
         // consume all the chunks
         let mut buf = vec![0; 4096];
         loop {
@@ -105,90 +254,319 @@ impl Drop for BodyReader {
     }
 }
 
+/// parse the `Content-Length` headers of the message
+///
+/// the value must be a plain decimal number that fits in `usize`, the
+/// duplicated or comma separated values are only allowed when they are
+/// identical
+pub(crate) fn content_length(headers: &HeaderMap) -> io::Result<Option<usize>> {
+    let invalid = |msg: &str| io::Error::from(Error::Parse(msg.to_owned()));
+    let mut len = None;
+    for value in headers.get_all(CONTENT_LENGTH) {
+        for s in value.as_bytes().split(|&b| b == b',') {
+            let s = s.trim_ascii();
+            if s.is_empty() || !s.iter().all(u8::is_ascii_digit) {
+                return Err(invalid("invalid content length"));
+            }
+            // the digits are valid utf8
+            let n = unsafe { str::from_utf8_unchecked(s) }
+                .parse::<usize>()
+                .map_err(|_| invalid("content length overflow"))?;
+            match len {
+                Some(l) if l != n => return Err(invalid("conflicting content length")),
+                _ => len = Some(n),
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// return true if the final transfer coding of the message is chunked
+pub(crate) fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TRANSFER_ENCODING)
+        .iter()
+        .next_back()
+        .and_then(|v| v.as_bytes().rsplit(|&b| b == b',').next())
+        .is_some_and(|s| s.trim_ascii().eq_ignore_ascii_case(b"chunked"))
+}
+
 fn eat(rdr: &mut dyn Read, bytes: &[u8]) -> io::Result<()> {
     let mut buf = [0];
     for &b in bytes.iter() {
         match rdr.read(&mut buf)? {
             1 if buf[0] == b => {}
             _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Invalid characters found",
-                ));
+                return Err(Error::InvalidChunk("Invalid characters found".to_owned()).into());
             }
         }
     }
     Ok(())
 }
 
-/// Chunked chunks start with 1*HEXDIGIT, indicating the size of the chunk.
-fn read_chunk_size(rdr: &mut dyn Read) -> io::Result<usize> {
-    macro_rules! byte (
-        ($rdr:ident) => ({
-            let mut buf = [0];
-            match $rdr.read(&mut buf)? {
-                1 => buf[0],
-                _ => return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Invalid chunk size line, read byte",
-                )),
-            }
-        })
-    );
+/// the maximum size of all the trailer fields
+const MAX_TRAILERS_SIZE: usize = 64 << 10;
+
+// read the trailer fields until the empty line, the framing fields are ignored
+fn read_trailers(rdr: &mut dyn Read, trailers: &mut HeaderMap) -> io::Result<()> {
+    let invalid = |msg: &str| io::Error::from(Error::InvalidChunk(msg.to_owned()));
+    let mut line = Vec::new();
     let mut size = 0;
-    let mut in_ext = false;
-    let mut in_chunk_size = true;
     loop {
-        match byte!(rdr) {
-            b @ b'0'..=b'9' if in_chunk_size => {
-                size <<= 4;
-                size += (b - b'0') as usize;
-            }
-            b @ b'a'..=b'f' if in_chunk_size => {
-                size <<= 4;
-                size += (b + 10 - b'a') as usize;
+        line.clear();
+        let mut buf = [0];
+        while !line.ends_with(b"\r\n") {
+            if rdr.read(&mut buf)? == 0 {
+                return Err(Error::ConnectionClosed.into());
             }
-            b @ b'A'..=b'F' if in_chunk_size => {
-                size <<= 4;
-                size += (b + 10 - b'A') as usize;
+            line.push(buf[0]);
+            size += 1;
+            if size > MAX_TRAILERS_SIZE {
+                return Err(invalid("trailers too large"));
             }
-            b'\r' => match byte!(rdr) {
-                b'\n' => break,
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "Invalid chunk size line, read new line",
-                    ));
-                }
-            },
-            // If we weren't in the extension yet, the ";" signals its start
-            b';' if !in_ext => {
-                in_ext = true;
-                in_chunk_size = false;
-            }
-            // "Linear white space" is ignored between the chunk size and the
-            // extension separator token (";") due to the "implied *LWS rule".
-            b'\t' | b' ' if !in_ext & !in_chunk_size => {}
-            // LWS can follow the chunk size, but no more digits can come
-            b'\t' | b' ' if in_chunk_size => in_chunk_size = false,
-            // We allow any arbitrary octet once we are in the extension, since
-            // they all get ignored anyway. According to the HTTP spec, valid
-            // extensions would have a more strict syntax:
-            //     (token ["=" (token | quoted-string)])
-            // but we gain nothing by rejecting an otherwise valid chunk size.
-            ext if in_ext => {
-                error!("chunk extension byte={}", ext);
+        }
+        let line = &line[..line.len() - 2];
+        if line.is_empty() {
+            return Ok(());
+        }
+        let pos = line
+            .iter()
+            .position(|&b| b == b':')
+            .ok_or_else(|| invalid("invalid trailer field"))?;
+        let name = HeaderName::from_bytes(&line[..pos])
+            .map_err(|_| invalid("invalid trailer field name"))?;
+        let value = HeaderValue::from_bytes(line[pos + 1..].trim_ascii())
+            .map_err(|_| invalid("invalid trailer field value"))?;
+        if name != CONTENT_LENGTH && name != TRANSFER_ENCODING {
+            trailers.append(name, value);
+        }
+    }
+}
+
+/// the maximum length of the chunk size line, including the extensions
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// Chunked chunks start with 1*HEXDIGIT, indicating the size of the chunk,
+/// followed by the optional extensions and CRLF
+///
+/// ```text
+/// chunk-size [ chunk-ext ] CRLF
+/// chunk-ext = *( BWS ";" BWS chunk-ext-name [ BWS "=" BWS chunk-ext-val ] )
+/// chunk-ext-val = token / quoted-string
+/// ```
+fn read_chunk_size(rdr: &mut dyn Read) -> io::Result<(usize, Extensions)> {
+    let mut line = Vec::new();
+    let mut buf = [0];
+    while !line.ends_with(b"\r\n") {
+        if rdr.read(&mut buf)? == 0 {
+            return Err(Error::ConnectionClosed.into());
+        }
+        if line.len() == MAX_CHUNK_LINE {
+            return Err(invalid_chunk("chunk size line too long"));
+        }
+        line.push(buf[0]);
+    }
+    let line = &line[..line.len() - 2];
+
+    let digits = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+    if digits == 0 {
+        return Err(invalid_chunk("invalid chunk size"));
+    }
+    let mut size: usize = 0;
+    for &b in &line[..digits] {
+        let digit = (b as char).to_digit(16).unwrap() as usize;
+        size = size
+            .checked_mul(16)
+            .and_then(|s| s.checked_add(digit))
+            .ok_or_else(|| invalid_chunk("chunk size overflow"))?;
+    }
+    trace!("chunk size={:?}", size);
+
+    let mut extensions = Vec::new();
+    let mut rest = skip_ws(&line[digits..]);
+    while !rest.is_empty() {
+        rest = match rest.strip_prefix(b";") {
+            Some(rest) => skip_ws(rest),
+            None => return Err(invalid_chunk("invalid chunk extension")),
+        };
+        let (name, r) = token(rest)?;
+        rest = skip_ws(r);
+        let value = match rest.strip_prefix(b"=") {
+            Some(r) => {
+                let r = skip_ws(r);
+                let (value, r) = if r.starts_with(b"\"") {
+                    quoted_string(r)?
+                } else {
+                    token(r)?
+                };
+                rest = skip_ws(r);
+                Some(value)
             }
-            // Finally, if we aren't in the extension and we're reading any
-            // other octet, the chunk size line is invalid!
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Invalid chunk size line, unkonw byte",
-                ));
+            None => None,
+        };
+        extensions.push((name, value));
+    }
+    Ok((size, extensions))
+}
+
+fn invalid_chunk(msg: &str) -> io::Error {
+    Error::InvalidChunk(msg.to_owned()).into()
+}
+
+fn skip_ws(s: &[u8]) -> &[u8] {
+    let n = s.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+    &s[n..]
+}
+
+// split the leading token
+fn token(s: &[u8]) -> io::Result<(String, &[u8])> {
+    let is_tchar = |b: &u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b);
+    let n = s.iter().take_while(|b| is_tchar(b)).count();
+    if n == 0 {
+        return Err(invalid_chunk("invalid chunk extension token"));
+    }
+    // the token chars are valid utf8
+    let token = unsafe { str::from_utf8_unchecked(&s[..n]) };
+    Ok((token.to_owned(), &s[n..]))
+}
+
+// split the leading quoted string, the quoted pairs are unescaped
+fn quoted_string(s: &[u8]) -> io::Result<(String, &[u8])> {
+    let invalid = || invalid_chunk("invalid chunk extension quoted string");
+    let is_text = |b: u8| b == b'\t' || b == b' ' || (0x21..=0x7e).contains(&b) || b >= 0x80;
+    let mut value = Vec::new();
+    let mut iter = s.iter().copied().enumerate().skip(1);
+    while let Some((i, b)) = iter.next() {
+        match b {
+            b'"' => {
+                let value = String::from_utf8_lossy(&value).into_owned();
+                return Ok((value, &s[i + 1..]));
             }
+            b'\\' => match iter.next() {
+                Some((_, b)) if is_text(b) => value.push(b),
+                _ => return Err(invalid()),
+            },
+            b if is_text(b) => value.push(b),
+            _ => return Err(invalid()),
         }
     }
-    trace!("chunk size={:?}", size);
-    Ok(size)
+    Err(invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(data: &'static [u8]) -> (BodyReader, Rc<RefCell<ChunkInfo>>) {
+        let info = Rc::new(RefCell::new(ChunkInfo::default()));
+        let reader = Rc::new(RefCell::new(io::Cursor::new(data)));
+        (ChunkReader(reader, None, info.clone()), info)
+    }
+
+    #[test]
+    fn chunk_trailers() {
+        let (mut reader, info) = chunked(
+            b"5\r\nhello\r\n0\r\nGrpc-Status: 0\r\nx-sum:  abc \r\n\
+              content-length: 5\r\n\r\n",
+        );
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+        let info = info.borrow();
+        let trailers = info.trailers();
+        assert_eq!(trailers.len(), 2);
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["x-sum"], "abc");
+
+        let (mut reader, _) = chunked(b"0\r\nno colon\r\n\r\n");
+        let e = reader.read(&mut [0; 8]).unwrap_err();
+        assert!(matches!(Error::from(e), Error::InvalidChunk(_)));
+    }
+
+    #[test]
+    fn lower_limit() {
+        let sized = |data: &'static [u8]| {
+            let reader = Rc::new(RefCell::new(io::Cursor::new(data)));
+            SizedReader(reader, data.len())
+        };
+        let exceeded = Rc::new(Cell::new(false));
+        let mut reader = sized(b"hello world");
+        reader.limit(Some(11), exceeded.clone());
+        let mut buf = [0; 6];
+        assert_eq!(reader.read(&mut buf).unwrap(), 6);
+        // the limit is lowered below the size already read
+        reader.limit(Some(4), exceeded.clone());
+        assert!(exceeded.get());
+        let e = reader.read(&mut buf).unwrap_err();
+        assert!(matches!(Error::from(e), Error::BodyTooLarge));
+
+        // the limit is lowered below the size of the rest body
+        let exceeded = Rc::new(Cell::new(false));
+        let mut reader = sized(b"hello world");
+        reader.limit(None, exceeded.clone());
+        assert_eq!(reader.read(&mut buf).unwrap(), 6);
+        reader.limit(Some(8), exceeded.clone());
+        assert!(!exceeded.get());
+        let e = reader.read(&mut buf).unwrap_err();
+        assert!(matches!(Error::from(e), Error::BodyTooLarge));
+        assert!(exceeded.get());
+    }
+
+    fn chunk_size(line: &str) -> io::Result<(usize, Extensions)> {
+        read_chunk_size(&mut io::Cursor::new(line.as_bytes()))
+    }
+
+    fn ext(name: &str, value: Option<&str>) -> (String, Option<String>) {
+        (name.to_owned(), value.map(str::to_owned))
+    }
+
+    #[test]
+    fn chunk_extensions() {
+        assert_eq!(chunk_size("1aF\r\n").unwrap(), (0x1af, vec![]));
+        let (size, exts) = chunk_size("5 ; a = 1;b;c=\"x \\\"y\\\" ;z\"\r\n").unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(
+            exts,
+            vec![
+                ext("a", Some("1")),
+                ext("b", None),
+                ext("c", Some("x \"y\" ;z"))
+            ]
+        );
+
+        let invalid = [
+            "\r\n",
+            "x\r\n",
+            "5 5\r\n",
+            "5;\r\n",
+            "5;a=\r\n",
+            "5;a=\"open\r\n",
+            "5;a=b c\r\n",
+            "5;a\x01\r\n",
+            "10000000000000000\r\n",
+        ];
+        for line in invalid.iter() {
+            let e = chunk_size(line).unwrap_err();
+            assert!(
+                matches!(Error::from(e), Error::InvalidChunk(_)),
+                "{:?}",
+                line
+            );
+        }
+        let long = format!("5;a={}\r\n", "x".repeat(MAX_CHUNK_LINE));
+        assert!(chunk_size(&long).is_err());
+        // the bare LF never ends the line
+        let e = chunk_size("5\n").unwrap_err();
+        assert!(matches!(Error::from(e), Error::ConnectionClosed));
+
+        // the extensions of the last read chunk
+        let (mut reader, info) = chunked(b"3;n=1\r\nabc\r\n2;n=2\r\nde\r\n0\r\n\r\n");
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(info.borrow().extensions(), &[ext("n", Some("1"))][..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(info.borrow().extensions(), &[ext("n", Some("2"))][..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(info.borrow().extensions().is_empty());
+    }
 }
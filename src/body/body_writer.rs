@@ -3,23 +3,38 @@ use std::fmt;
 use std::io::{self, Write};
 use std::rc::Rc;
 
+use http::{HeaderMap, HeaderValue};
+
 use self::BodyWriter::*;
 
 pub enum BodyWriter {
     SizedWriter(Rc<RefCell<dyn Write>>, usize),
-    ChunkWriter(Rc<RefCell<dyn Write>>),
+    // the trailer fields are written after the last chunk
+    ChunkWriter(Rc<RefCell<dyn Write>>, Rc<RefCell<HeaderMap>>),
     // this is used to write all the data out when get drop
     EmptyWriter(Rc<RefCell<dyn Write>>),
+    // the content encoder that wraps the chunked writer
+    EncodeWriter(Box<dyn Write>),
     // this is used as a invalid place holder
     InvalidWriter,
 }
 
+/// the `Trailer` header value that announces the trailer fields
+pub(crate) fn trailer_names(trailers: &HeaderMap) -> Option<HeaderValue> {
+    let names: Vec<_> = trailers.keys().map(|k| k.as_str()).collect();
+    if names.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&names.join(", ")).ok()
+}
+
 impl fmt::Debug for BodyWriter {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         let name = match *self {
             SizedWriter(..) => "SizedWriter",
-            ChunkWriter(_) => "ChunkWriter",
+            ChunkWriter(..) => "ChunkWriter",
             EmptyWriter(_) => "EmptyWriter",
+            EncodeWriter(_) => "EncodeWriter",
             InvalidWriter => "Invalid",
         };
         write!(f, "BodyWriter {}", name)
@@ -38,8 +53,12 @@ impl Write for BodyWriter {
                 *remain -= n;
                 Ok(n)
             }
-            ChunkWriter(ref w) => {
+            ChunkWriter(ref w, _) => {
                 let chunk_size = buf.len();
+                if chunk_size == 0 {
+                    // the empty chunk would end the body
+                    return Ok(0);
+                }
                 let mut w = w.borrow_mut();
                 write!(w, "{:X}\r\n", chunk_size)?;
                 w.write_all(buf)?;
@@ -47,6 +66,7 @@ impl Write for BodyWriter {
                 Ok(chunk_size)
             }
             EmptyWriter(_) => Ok(0),
+            EncodeWriter(ref mut w) => w.write(buf),
             InvalidWriter => unreachable!(),
         }
     }
@@ -58,7 +78,7 @@ impl Write for BodyWriter {
                 let mut w = w.borrow_mut();
                 w.flush()
             }
-            ChunkWriter(ref w) => {
+            ChunkWriter(ref w, _) => {
                 let mut w = w.borrow_mut();
                 w.flush()
             }
@@ -66,6 +86,7 @@ impl Write for BodyWriter {
                 let mut w = w.borrow_mut();
                 w.flush()
             }
+            EncodeWriter(ref mut w) => w.flush(),
             InvalidWriter => unreachable!(),
         }
     }
@@ -83,16 +104,25 @@ impl Drop for BodyWriter {
                 }
                 w.flush().ok();
             }
-            ChunkWriter(ref w) => {
-                // write the chunk end and flush
+            ChunkWriter(ref w, ref trailers) => {
+                // write the chunk end with the trailers and flush
                 let mut w = w.borrow_mut();
-                w.write_all(b"0\r\n\r\n").ok();
+                w.write_all(b"0\r\n").ok();
+                for (key, value) in trailers.borrow().iter() {
+                    w.write_all(key.as_str().as_bytes()).ok();
+                    w.write_all(b": ").ok();
+                    w.write_all(value.as_bytes()).ok();
+                    w.write_all(b"\r\n").ok();
+                }
+                w.write_all(b"\r\n").ok();
                 w.flush().ok();
             }
             EmptyWriter(ref w) => {
                 let mut w = w.borrow_mut();
                 w.flush().ok();
             }
+            // the encoder finishes the data and the chunks on drop
+            EncodeWriter(_) => {}
             InvalidWriter => {}
         }
     }
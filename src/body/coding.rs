@@ -0,0 +1,196 @@
+//! the content codings of the message body
+//!
+//! the gzip and deflate codings need the `flate2` feature, and the
+//! br coding needs the `brotli` feature
+use std::io::{self, Read, Write};
+
+use http::header::*;
+
+/// the supported content coding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Coding {
+    #[cfg(feature = "brotli")]
+    Brotli,
+    #[cfg(feature = "flate2")]
+    Gzip,
+    #[cfg(feature = "flate2")]
+    Deflate,
+}
+
+impl Coding {
+    /// the supported codings in the order of preference
+    pub(crate) const ALL: &'static [Coding] = &[
+        #[cfg(feature = "brotli")]
+        Coding::Brotli,
+        #[cfg(feature = "flate2")]
+        Coding::Gzip,
+        #[cfg(feature = "flate2")]
+        Coding::Deflate,
+    ];
+
+    /// get the coding by the token of the `Content-Encoding` header
+    pub(crate) fn from_name(name: &[u8]) -> Option<Coding> {
+        Coding::ALL.iter().copied().find(|c| {
+            name.eq_ignore_ascii_case(c.name().as_bytes())
+                || (c.name() == "gzip" && name.eq_ignore_ascii_case(b"x-gzip"))
+        })
+    }
+
+    /// the token of the coding
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "brotli")]
+            Coding::Brotli => "br",
+            #[cfg(feature = "flate2")]
+            Coding::Gzip => "gzip",
+            #[cfg(feature = "flate2")]
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    /// wrap the writer with the encoder
+    ///
+    /// the encoded data is finished when the encoder is dropped
+    #[cfg_attr(
+        not(any(feature = "flate2", feature = "brotli")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn encoder<'a, W: Write + 'a>(self, writer: W) -> Box<dyn Write + 'a> {
+        match self {
+            #[cfg(feature = "brotli")]
+            Coding::Brotli => Box::new(brotli::CompressorWriter::new(writer, 4096, 5, 22)),
+            #[cfg(feature = "flate2")]
+            Coding::Gzip => Box::new(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            )),
+            // the deflate coding is the zlib format
+            #[cfg(feature = "flate2")]
+            Coding::Deflate => Box::new(flate2::write::ZlibEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            )),
+        }
+    }
+
+    /// wrap the reader with the decoder
+    #[cfg_attr(
+        not(any(feature = "flate2", feature = "brotli")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn decoder<'a, R: Read + 'a>(self, reader: R) -> Box<dyn Read + 'a> {
+        match self {
+            #[cfg(feature = "brotli")]
+            Coding::Brotli => Box::new(brotli::Decompressor::new(reader, 4096)),
+            #[cfg(feature = "flate2")]
+            Coding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            #[cfg(feature = "flate2")]
+            Coding::Deflate => Box::new(flate2::read::ZlibDecoder::new(reader)),
+        }
+    }
+
+    /// encode the whole data
+    pub(crate) fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        {
+            let mut encoder = self.encoder(&mut out);
+            encoder.write_all(data)?;
+        }
+        Ok(out)
+    }
+}
+
+/// parse the `Content-Encoding` headers in the order they are applied
+///
+/// the identity coding is skipped, `None` means some coding is unsupported
+pub(crate) fn content_codings(headers: &HeaderMap) -> Option<Vec<Coding>> {
+    let mut codings = Vec::new();
+    for value in headers.get_all(CONTENT_ENCODING) {
+        for name in value.as_bytes().split(|&b| b == b',') {
+            let name = name.trim_ascii();
+            if name.is_empty() || name.eq_ignore_ascii_case(b"identity") {
+                continue;
+            }
+            codings.push(Coding::from_name(name)?);
+        }
+    }
+    Some(codings)
+}
+
+/// the `Accept-Encoding` value of all the supported codings
+pub(crate) fn accept_encoding() -> Option<HeaderValue> {
+    let names: Vec<_> = Coding::ALL.iter().map(|c| c.name()).collect();
+    if names.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&names.join(", ")).ok()
+}
+
+/// choose the content coding that accepted by the `Accept-Encoding` headers
+///
+/// the coding with the highest quality wins, the ties are broken by the
+/// order of `Coding::ALL`. `None` means the identity coding
+pub(crate) fn negotiate(headers: &HeaderMap) -> Option<Coding> {
+    let mut qualities = [None; Coding::ALL.len()];
+    let mut wildcard = None;
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        for item in value.as_bytes().split(|&b| b == b',') {
+            let mut parts = item.split(|&b| b == b';');
+            let name = parts.next().unwrap_or_default().trim_ascii();
+            let q = parts
+                .filter_map(|p| {
+                    let p = p.trim_ascii();
+                    p.strip_prefix(b"q=").or_else(|| p.strip_prefix(b"Q="))
+                })
+                .next()
+                .map_or(Some(1.0), parse_quality);
+            // the invalid quality disables the coding
+            let q = q.unwrap_or(0.0);
+            if name == b"*" {
+                wildcard = Some(q);
+            } else if let Some(c) = Coding::from_name(name) {
+                let i = Coding::ALL.iter().position(|&a| a == c).unwrap();
+                qualities[i] = Some(q);
+            }
+        }
+    }
+
+    let mut best = None;
+    for (i, &c) in Coding::ALL.iter().enumerate() {
+        let q = qualities[i].or(wildcard).unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, b)| q > b) {
+            best = Some((c, q));
+        }
+    }
+    best.map(|(c, _)| c)
+}
+
+// parse the quality value that between 0 and 1
+fn parse_quality(s: &[u8]) -> Option<f32> {
+    let q: f32 = std::str::from_utf8(s).ok()?.parse().ok()?;
+    Some(q).filter(|q| (0.0..=1.0).contains(q))
+}
+
+#[cfg(all(test, feature = "flate2"))]
+mod tests {
+    use super::*;
+
+    fn accept(value: &'static str) -> Option<&'static str> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        negotiate(&headers).map(Coding::name)
+    }
+
+    #[test]
+    fn negotiate_coding() {
+        assert_eq!(accept("gzip"), Some("gzip"));
+        assert_eq!(accept("identity"), None);
+        assert_eq!(accept("deflate;q=0.5, gzip;q=0.2"), Some("deflate"));
+        assert_eq!(accept("X-GZIP, deflate"), Some("gzip"));
+        assert_eq!(accept("gzip;q=0, deflate;q=bad"), None);
+        // the wildcard matches the codings that not listed
+        let any = accept("gzip;q=0, *;q=0.1");
+        assert!(any.is_some() && any != Some("gzip"));
+        assert_eq!(negotiate(&HeaderMap::new()), None);
+    }
+}
@@ -1,4 +1,7 @@
 mod body_reader;
 mod body_writer;
-pub use self::body_reader::BodyReader;
+pub(crate) mod coding;
+pub(crate) use self::body_reader::{content_length, is_chunked};
+pub use self::body_reader::{BodyLimit, BodyReader, ChunkInfo};
+pub(crate) use self::body_writer::trailer_names;
 pub use self::body_writer::BodyWriter;
@@ -8,6 +8,8 @@ pub struct BufferIo<T> {
     inner: T,
     reader_buf: BytesMut,
     writer_buf: (Vec<u8>, usize),
+    // skip the flush, the owner would flush the data later
+    defer_flush: bool,
 }
 
 const INIT_BUFFER_SIZE: usize = 4096;
@@ -24,6 +26,7 @@ impl<T> BufferIo<T> {
             inner: io,
             reader_buf: BytesMut::with_capacity(cap),
             writer_buf: (vec![0u8; cap], 0),
+            defer_flush: false,
         }
     }
 
@@ -31,6 +34,14 @@ impl<T> BufferIo<T> {
     pub fn inner_mut(&mut self) -> &mut T {
         &mut self.inner
     }
+
+    /// defer the flush, so that multiple responses can be batched
+    /// the data is only written out when the buffer is full or after
+    /// the flag is cleared and `flush` is called again
+    #[inline]
+    pub fn set_defer_flush(&mut self, defer: bool) {
+        self.defer_flush = defer;
+    }
 }
 
 impl<T: Read> BufferIo<T> {
@@ -85,7 +96,7 @@ impl<T: Write> Write for BufferIo<T> {
         use std::ptr;
         let buf_len = self.writer_buf.0.len();
         if buf_len == self.writer_buf.1 {
-            self.flush()?;
+            self.flush_buf()?;
         }
 
         let remain = buf_len - self.writer_buf.1;
@@ -101,10 +112,21 @@ impl<T: Write> Write for BufferIo<T> {
 
     #[inline]
     fn flush(&mut self) -> io::Result<()> {
+        if self.defer_flush {
+            return Ok(());
+        }
+        self.flush_buf()
+    }
+}
+
+impl<T: Write> BufferIo<T> {
+    // write out all the buffered data
+    fn flush_buf(&mut self) -> io::Result<()> {
         let buf = &self.writer_buf.0[0..self.writer_buf.1];
         self.inner.write_all(buf)?;
         self.writer_buf.1 = 0;
-        Ok(())
+        // the inner stream may have its own buffer, e.g. a tls session
+        self.inner.flush()
     }
 }
 
@@ -159,7 +181,7 @@ mod tests {
     #[test]
     // the minimum size is 31
     fn test_resize() {
-        let raw = vec![1u8; 100];
+        let raw = [1u8; 100];
         let mut rdr = BufferIo::with_capacity(&raw[..], 65);
         rdr.bump_read().unwrap();
         assert_eq!(rdr.get_reader_buf().len(), 65);
@@ -180,4 +202,19 @@ mod tests {
         let n = wrt.write(&data).unwrap();
         assert_eq!(n, 40);
     }
+
+    #[test]
+    fn test_defer_flush() {
+        let mut wrt = BufferIo::with_capacity(Vec::new(), 8);
+        wrt.set_defer_flush(true);
+        wrt.write_all(b"foo").unwrap();
+        wrt.flush().unwrap();
+        assert!(wrt.inner_mut().is_empty());
+        wrt.write_all(b"barbaz").unwrap();
+        // the full buffer is always written out
+        assert_eq!(wrt.inner_mut().as_slice(), b"foobarba");
+        wrt.set_defer_flush(false);
+        wrt.flush().unwrap();
+        assert_eq!(wrt.inner_mut().as_slice(), b"foobarbaz");
+    }
 }
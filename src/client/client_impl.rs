@@ -1,41 +1,433 @@
 use std::cell::RefCell;
-use std::io;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::net::ToSocketAddrs;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use bytes::Buf;
+use http::header::*;
 use http::{Method, Uri};
 use may::net::TcpStream;
 
+use crate::body::coding;
 use crate::buffer::BufferIo;
-use crate::client::{Request, Response};
+#[cfg(feature = "rustls")]
+use crate::client::tls::{TlsConnector, TlsStream};
+use crate::client::{ClientBuilder, Request, Response};
+use crate::limits::HeadLimits;
+use crate::websocket::{self, Role, WebSocket};
+use crate::Error;
+
+/// the underlying io of a client connection
+pub(crate) enum Io {
+    Tcp(TcpStream),
+    #[cfg(feature = "rustls")]
+    Tls(Box<TlsStream>),
+}
+
+/// the underlying stream of a client connection
+///
+/// the reads and writes fail with `Error::Timeout` once the request
+/// deadline is reached
+pub(crate) struct Stream {
+    io: Io,
+    // the read/write timeout set by the user
+    timeout: Option<Duration>,
+    // the deadline of the current request
+    deadline: Option<Instant>,
+    // the socket timeout is changed by the deadline
+    armed: bool,
+}
+
+impl Stream {
+    pub(crate) fn new(io: Io) -> Self {
+        Stream {
+            io,
+            timeout: None,
+            deadline: None,
+            armed: false,
+        }
+    }
+
+    /// the raw tcp stream
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self.io {
+            Io::Tcp(ref s) => s,
+            #[cfg(feature = "rustls")]
+            Io::Tls(ref s) => s.get_ref(),
+        }
+    }
+
+    /// whether the stream is a tls stream
+    pub(crate) fn is_tls(&self) -> bool {
+        match self.io {
+            Io::Tcp(_) => false,
+            #[cfg(feature = "rustls")]
+            Io::Tls(_) => true,
+        }
+    }
+
+    /// set the read/write timeout of the socket
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout = timeout;
+        let s = self.tcp();
+        s.set_read_timeout(timeout)?;
+        s.set_write_timeout(timeout)
+    }
+
+    /// set the deadline of the current request, `None` clears it
+    pub(crate) fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    // limit the socket timeout to the time left before the deadline
+    fn arm(&mut self) -> io::Result<()> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None if self.armed => {
+                // restore the user timeout
+                self.armed = false;
+                return self.set_timeout(self.timeout);
+            }
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Timeout.into());
+        }
+        let left = deadline - now;
+        let t = self.timeout.map_or(left, |t| t.min(left));
+        self.armed = true;
+        let s = self.tcp();
+        s.set_read_timeout(Some(t))?;
+        s.set_write_timeout(Some(t))
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.io {
+            Io::Tcp(ref s) => write!(f, "Tcp({:?})", s.peer_addr()),
+            #[cfg(feature = "rustls")]
+            Io::Tls(ref s) => write!(f, "Tls({:?})", s.get_ref().peer_addr()),
+        }
+    }
+}
+
+impl Read for Stream {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.arm()?;
+        match self.io {
+            Io::Tcp(ref mut s) => s.read(buf),
+            #[cfg(feature = "rustls")]
+            Io::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.arm()?;
+        match self.io {
+            Io::Tcp(ref mut s) => s.write(buf),
+            #[cfg(feature = "rustls")]
+            Io::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        match self.io {
+            Io::Tcp(ref mut s) => s.flush(),
+            #[cfg(feature = "rustls")]
+            Io::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// connect to the address, try each resolved address within the timeout
+pub(crate) fn connect_tcp<A: ToSocketAddrs>(
+    remote: A,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return TcpStream::connect(remote),
+    };
+    let mut err = None;
+    for addr in remote.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(s) => return Ok(s),
+            Err(e) => err = Some(e),
+        }
+    }
+    Err(err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address resolved")))
+}
+
+// the authority of the `Host` header, the default port is omitted
+fn authority(host: &str, port: u16, default_port: u16) -> String {
+    match port {
+        p if p == default_port && host.contains(':') => format!("[{}]", host),
+        p if p == default_port => host.to_owned(),
+        p if host.contains(':') => format!("[{}]:{}", host, p),
+        p => format!("{}:{}", host, p),
+    }
+}
 
 /// this is just a simple client connector
 #[derive(Debug)]
 pub struct HttpClient {
-    conn: Rc<RefCell<BufferIo<TcpStream>>>,
+    conn: Rc<RefCell<BufferIo<Stream>>>,
+    limits: HeadLimits,
+    // the total timeout of each request
+    timeout: Option<Duration>,
+    // the headers that added to each request
+    headers: HeaderMap,
+    // write the absolute uri in the request line
+    absolute_form: bool,
+    // decode the compressed response body
+    decompress: bool,
+}
+
+/// the default headers of the client requests
+pub(super) fn default_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, HeaderValue::from_static("may_http"));
+    headers.insert(ACCEPT, HeaderValue::from_static("*/*"));
+    if let Some(codings) = coding::accept_encoding() {
+        headers.insert(ACCEPT_ENCODING, codings);
+    }
+    headers
 }
 
 impl HttpClient {
+    /// create a builder for the pooled client
+    ///
+    /// the pooled client accepts absolute uris for any host and
+    /// reuses the keep-alive connections
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
     /// create HttpClient connect to the given address
     pub fn connect<A: ToSocketAddrs>(remote: A) -> io::Result<Self> {
         // TODO: use async dns resolve
         let stream = TcpStream::connect(remote)?;
-        let stream = BufferIo::new(stream);
-        Ok(HttpClient {
-            conn: Rc::new(RefCell::new(stream)),
-        })
+        let host = stream.peer_addr()?.to_string();
+        Ok(Self::from_stream(Io::Tcp(stream), &host))
+    }
+
+    /// create HttpClient connect to the given address within the timeout
+    ///
+    /// each resolved address is tried with the timeout in turn
+    pub fn connect_timeout<A: ToSocketAddrs>(remote: A, timeout: Duration) -> io::Result<Self> {
+        let stream = connect_tcp(remote, Some(timeout))?;
+        let host = stream.peer_addr()?.to_string();
+        Ok(Self::from_stream(Io::Tcp(stream), &host))
+    }
+
+    /// create HttpClient connect to the host by name
+    ///
+    /// unlike `connect`, the `Host` header of the requests is the name
+    /// instead of the resolved address
+    pub fn connect_named(host: &str, port: u16) -> io::Result<Self> {
+        Self::connect_named_impl(host, port, None)
+    }
+
+    /// create HttpClient connect to the host by name within the timeout
+    pub fn connect_named_timeout(host: &str, port: u16, timeout: Duration) -> io::Result<Self> {
+        Self::connect_named_impl(host, port, Some(timeout))
+    }
+
+    fn connect_named_impl(host: &str, port: u16, timeout: Option<Duration>) -> io::Result<Self> {
+        let stream = connect_tcp((host, port), timeout)?;
+        Ok(Self::from_stream(
+            Io::Tcp(stream),
+            &authority(host, port, 80),
+        ))
+    }
+
+    /// create HttpClient connect to the given address over tls
+    ///
+    /// the `host` is used for SNI and to verify the server certificate
+    #[cfg(feature = "rustls")]
+    pub fn connect_tls<A: ToSocketAddrs>(
+        remote: A,
+        host: &str,
+        connector: &TlsConnector,
+    ) -> io::Result<Self> {
+        Self::connect_tls_impl(remote, host, connector, None)
+    }
+
+    /// create HttpClient connect to the given address over tls within the timeout
+    ///
+    /// the timeout covers both the tcp connect and the tls handshake
+    #[cfg(feature = "rustls")]
+    pub fn connect_tls_timeout<A: ToSocketAddrs>(
+        remote: A,
+        host: &str,
+        connector: &TlsConnector,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        Self::connect_tls_impl(remote, host, connector, Some(timeout))
+    }
+
+    #[cfg(feature = "rustls")]
+    fn connect_tls_impl<A: ToSocketAddrs>(
+        remote: A,
+        host: &str,
+        connector: &TlsConnector,
+        timeout: Option<Duration>,
+    ) -> io::Result<Self> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let stream = connect_tcp(remote, timeout)?;
+        let authority = authority(host, stream.peer_addr()?.port(), 443);
+        let stream = connector.connect(host, stream, deadline)?;
+        Ok(Self::from_stream(Io::Tls(Box::new(stream)), &authority))
+    }
+
+    /// create HttpClient connect to the host of the `https` uri
+    ///
+    /// the port defaults to 443 if the uri doesn't contain one
+    #[cfg(feature = "rustls")]
+    pub fn connect_https(uri: &Uri, connector: &TlsConnector) -> io::Result<Self> {
+        Self::connect_https_impl(uri, connector, None)
+    }
+
+    /// create HttpClient connect to the host of the `https` uri within the timeout
+    ///
+    /// the timeout covers both the tcp connect and the tls handshake
+    #[cfg(feature = "rustls")]
+    pub fn connect_https_timeout(
+        uri: &Uri,
+        connector: &TlsConnector,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        Self::connect_https_impl(uri, connector, Some(timeout))
+    }
+
+    #[cfg(feature = "rustls")]
+    fn connect_https_impl(
+        uri: &Uri,
+        connector: &TlsConnector,
+        timeout: Option<Duration>,
+    ) -> io::Result<Self> {
+        let host = uri
+            .host()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "uri without host"))?;
+        let port = uri.port_u16().unwrap_or(443);
+        // the ipv6 host is wrapped with brackets in the uri
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Self::connect_tls_impl((host, port), host, connector, timeout)
+    }
+
+    // the `host` is the default `Host` header of the requests
+    fn from_stream(io: Io, host: &str) -> Self {
+        let mut headers = default_headers();
+        if let Ok(host) = host.parse() {
+            headers.insert(HOST, host);
+        }
+        let conn = BufferIo::new(Stream::new(io));
+        Self::from_conn(conn, HeadLimits::default(), None, headers)
+    }
+
+    pub(super) fn from_conn(
+        conn: BufferIo<Stream>,
+        limits: HeadLimits,
+        timeout: Option<Duration>,
+        headers: HeaderMap,
+    ) -> Self {
+        HttpClient {
+            conn: Rc::new(RefCell::new(conn)),
+            limits,
+            timeout,
+            headers,
+            absolute_form: false,
+            decompress: true,
+        }
+    }
+
+    pub(super) fn into_conn(self) -> Rc<RefCell<BufferIo<Stream>>> {
+        self.conn
+    }
+
+    // make sure we don't send a https request in cleartext
+    fn check_scheme(&self, uri: &Uri) -> io::Result<()> {
+        let secure = matches!(uri.scheme_str(), Some("https") | Some("wss"));
+        if secure && !self.conn.borrow_mut().inner_mut().is_tls() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "https uri on a plain connection",
+            ));
+        }
+        Ok(())
     }
 
     /// set both read/write timeout for the connection
+    ///
+    /// the timeout applies to each read or write, see `set_request_timeout`
+    /// for the total timeout of a request
     pub fn set_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
-        {
-            let mut s = self.conn.borrow_mut();
-            let s = s.inner_mut();
-            s.set_read_timeout(timeout).unwrap();
-            s.set_write_timeout(timeout).unwrap();
-        }
+        let mut s = self.conn.borrow_mut();
+        s.inner_mut().set_timeout(timeout).unwrap();
+        drop(s);
+        self
+    }
+
+    /// set the total timeout of each request, default is `None`
+    ///
+    /// the deadline starts when the request is created and covers
+    /// sending the request and receiving the response head and body,
+    /// after that the io fails with `Error::Timeout`. it can be
+    /// overridden by `Request::set_timeout`
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// get the headers that added to each request
+    ///
+    /// the defaults are `User-Agent: may_http`, `Accept: */*`, the
+    /// `Accept-Encoding` of the supported codings and the `Host` of the
+    /// connected server, they can be changed or removed.
+    /// the `Host` is replaced by the authority of an absolute request uri
+    pub fn default_headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    /// write the absolute uri in the request line, default is false
+    ///
+    /// it's used to send the requests through a http proxy, otherwise
+    /// only the path and query of the uri are written
+    pub fn set_absolute_form(&mut self, absolute: bool) -> &mut Self {
+        self.absolute_form = absolute;
+        self
+    }
+
+    /// decode the compressed response body, default is true
+    ///
+    /// the gzip and deflate codings need the `flate2` feature, and the br
+    /// coding needs the `brotli` feature. the `Content-Encoding` and the
+    /// `Content-Length` headers are removed from the decoded response
+    pub fn set_decompress(&mut self, decompress: bool) -> &mut Self {
+        self.decompress = decompress;
+        self
+    }
+
+    /// set the maximum number of response headers, default is 64
+    pub fn set_max_headers(&mut self, max: usize) -> &mut Self {
+        self.limits.max_headers = max;
+        self
+    }
+
+    /// set the maximum size of the response head, default is 64KiB
+    ///
+    /// the limit is checked while receiving the head
+    pub fn set_max_head_size(&mut self, size: usize) -> &mut Self {
+        self.limits.max_head_size = size;
         self
     }
 
@@ -46,11 +438,7 @@ impl HttpClient {
     /// client.send_request(client.new_request(GET, uri))
     /// ```
     pub fn get(&mut self, uri: Uri) -> io::Result<Response> {
-        let mut req = Request::new(self.conn.clone());
-        *req.uri_mut() = uri;
-        // send out the request by drop the req
-        drop(req);
-        self.get_rsp()
+        self.call(Method::GET, uri, None)
     }
 
     /// create a post request with the uri and data, return the response
@@ -62,13 +450,49 @@ impl HttpClient {
     ///  client.send_request()
     /// ```
     pub fn post<T: Buf>(&mut self, uri: Uri, data: T) -> io::Result<Response> {
-        let mut req = Request::new(self.conn.clone());
-        *req.method_mut() = Method::POST;
-        *req.uri_mut() = uri;
-        req.send(data.bytes())?;
-        // send out the request by drop the req
+        self.call(Method::POST, uri, Some(data.bytes()))
+    }
+
+    /// create a put request with the uri and data, return the response
+    pub fn put<T: Buf>(&mut self, uri: Uri, data: T) -> io::Result<Response> {
+        self.call(Method::PUT, uri, Some(data.bytes()))
+    }
+
+    /// create a patch request with the uri and data, return the response
+    pub fn patch<T: Buf>(&mut self, uri: Uri, data: T) -> io::Result<Response> {
+        self.call(Method::PATCH, uri, Some(data.bytes()))
+    }
+
+    /// create a DELETE request to the specified uri and return the response
+    ///
+    /// use `new_request` to send a DELETE request with a body
+    pub fn delete(&mut self, uri: Uri) -> io::Result<Response> {
+        self.call(Method::DELETE, uri, None)
+    }
+
+    /// create a HEAD request to the specified uri and return the response
+    ///
+    /// the response has no body
+    pub fn head(&mut self, uri: Uri) -> io::Result<Response> {
+        self.call(Method::HEAD, uri, None)
+    }
+
+    /// create an OPTIONS request to the specified uri and return the response
+    pub fn options(&mut self, uri: Uri) -> io::Result<Response> {
+        self.call(Method::OPTIONS, uri, None)
+    }
+
+    // send the request with the optional body and return the response
+    fn call(&mut self, method: Method, uri: Uri, body: Option<&[u8]>) -> io::Result<Response> {
+        self.check_scheme(&uri)?;
+        let mut req = self.new_request(method.clone(), uri);
+        if let Some(body) = body {
+            req.send(body)?;
+        }
+        // send out the request
+        req.finish()?;
         drop(req);
-        self.get_rsp()
+        self.get_rsp(&method)
     }
 
     /// create a request with specified method and uri
@@ -79,7 +503,15 @@ impl HttpClient {
     pub fn new_request(&self, method: Method, uri: Uri) -> Request {
         let mut req = Request::new(self.conn.clone());
         *req.method_mut() = method;
+        *req.headers_mut() = self.headers.clone();
+        // the host of the absolute uri takes precedence
+        if let Some(Ok(host)) = uri.authority().map(|a| a.as_str().parse()) {
+            req.headers_mut().insert(HOST, host);
+        }
         *req.uri_mut() = uri;
+        req.set_absolute_form(self.absolute_form);
+        req.set_stream(self.conn.clone());
+        req.set_timeout(self.timeout);
         req
     }
 
@@ -88,35 +520,432 @@ impl HttpClient {
     /// note that you can only send the request that created form the
     /// same client, or call this function will panic
     #[inline]
-    pub fn send_request(&mut self, req: Request) -> io::Result<Response> {
-        use std::io::Write;
+    pub fn send_request(&mut self, mut req: Request) -> io::Result<Response> {
         let conn: Rc<RefCell<dyn Write>> = self.conn.clone();
-        assert_eq!(Rc::ptr_eq(&conn, req.conn()), true);
+        assert!(Rc::ptr_eq(&conn, req.conn()));
+        if let Err(e) = self.check_scheme(req.uri()) {
+            // don't send out the request
+            req.discard();
+            return Err(e);
+        }
+        let method = req.method().clone();
+        req.finish()?;
         drop(req);
-        self.get_rsp()
+        self.get_rsp(&method)
+    }
+
+    /// upgrade the connection to a websocket with default configuration
+    ///
+    /// the upgrade request is sent to the uri, and the connection is
+    /// taken over by the returned websocket after the handshake is done
+    pub fn websocket(self, uri: Uri) -> io::Result<WebSocket> {
+        self.websocket_with_config(uri, websocket::Config::default())
+    }
+
+    /// upgrade the connection to a websocket with the given configuration
+    pub fn websocket_with_config(
+        mut self,
+        uri: Uri,
+        config: websocket::Config,
+    ) -> io::Result<WebSocket> {
+        let key = websocket::client_key()?;
+        let mut req = self.new_request(Method::GET, uri);
+        {
+            let headers = req.headers_mut();
+            headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+            headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+            headers.insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+            headers.insert(SEC_WEBSOCKET_KEY, key.parse().unwrap());
+        }
+        let rsp = self.send_request(req)?;
+        websocket::check_response(&rsp, &key)?;
+        drop(rsp);
+        // the websocket is not limited by the request deadline
+        self.conn.borrow_mut().inner_mut().set_deadline(None);
+        Ok(WebSocket::new(self.conn, Role::Client, config))
     }
 
     // get response from the connection
     #[inline]
-    fn get_rsp(&mut self) -> io::Result<Response> {
+    fn get_rsp(&mut self, method: &Method) -> io::Result<Response> {
         let mut stream = self.conn.borrow_mut();
         loop {
-            match super::response::decode(stream.get_reader_buf())? {
+            match super::response::decode(stream.get_reader_buf(), &self.limits)? {
                 None => {
                     // need more data
                     if stream.bump_read()? == 0 {
                         // break the connection
-                        return Err(io::Error::new(
-                            io::ErrorKind::UnexpectedEof,
-                            "connection breaked",
-                        ));
+                        return Err(Error::ConnectionClosed.into());
                     }
                 }
+                // skip the interim responses, they have no body
+                Some(rsp) if rsp.status().is_informational() && rsp.status() != 101 => {}
                 Some(mut rsp) => {
-                    rsp.set_reader(self.conn.clone());
+                    rsp.set_reader(self.conn.clone(), method, self.decompress);
                     return Ok(rsp);
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // a server that trickles the response body byte by byte
+    fn trickle_server(port: u16) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        thread::spawn(move || {
+            for s in listener.incoming() {
+                let mut s = s.unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0u8; 1024];
+                    let _ = s.read(&mut buf).unwrap();
+                    s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n")
+                        .unwrap();
+                    for _ in 0..10 {
+                        thread::sleep(Duration::from_millis(50));
+                        if s.write_all(b"x").is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    // reply the raw request head as the body
+    fn head_server(port: u16) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        thread::spawn(move || {
+            for s in listener.incoming() {
+                let mut s = s.unwrap();
+                thread::spawn(move || {
+                    let mut head = Vec::new();
+                    let mut buf = [0u8; 1];
+                    while s.read(&mut buf).unwrap_or(0) == 1 {
+                        head.push(buf[0]);
+                        if head.ends_with(b"\r\n\r\n") {
+                            let rsp = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                                head.len()
+                            );
+                            s.write_all(rsp.as_bytes()).unwrap();
+                            s.write_all(&head).unwrap();
+                            head.clear();
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    // reply each request with the next response of the connection script,
+    // the connection is closed after the script is done
+    fn script_server(port: u16, scripts: Vec<Vec<&'static [u8]>>) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        thread::spawn(move || {
+            for (s, script) in listener.incoming().zip(scripts) {
+                let mut s = s.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1];
+                for rsp in script {
+                    while !head.ends_with(b"\r\n\r\n") && s.read(&mut buf).unwrap() == 1 {
+                        head.push(buf[0]);
+                    }
+                    head.clear();
+                    s.write_all(rsp).unwrap();
+                }
+            }
+        });
+    }
+
+    // reply the method, the body framing and the body
+    fn echo(mut req: crate::server::Request, rsp: &mut crate::server::Response) {
+        let framing = match (
+            req.headers().get(CONTENT_LENGTH),
+            req.headers().get(TRANSFER_ENCODING),
+        ) {
+            (Some(len), _) => format!("length {}", len.to_str().unwrap()),
+            (None, Some(_)) => "chunked".to_owned(),
+            (None, None) => "none".to_owned(),
+        };
+        let mut body = String::new();
+        req.read_to_string(&mut body).unwrap();
+        let s = format!("{} {} {}", req.method(), framing, body);
+        rsp.send(s.as_bytes()).unwrap();
+    }
+
+    fn body(mut rsp: Response) -> String {
+        let mut s = String::new();
+        rsp.read_to_string(&mut s).unwrap();
+        s
+    }
+
+    #[test]
+    fn request_methods() {
+        let server = crate::server::HttpServer::new(echo)
+            .start("127.0.0.1:8105")
+            .unwrap();
+        let uri: Uri = "/".parse().unwrap();
+        let mut client = HttpClient::connect("127.0.0.1:8105").unwrap();
+
+        assert_eq!(body(client.get(uri.clone()).unwrap()), "GET none ");
+        let rsp = client.put(uri.clone(), &b"put"[..]).unwrap();
+        assert_eq!(body(rsp), "PUT length 3 put");
+        let rsp = client.patch(uri.clone(), &b""[..]).unwrap();
+        assert_eq!(body(rsp), "PATCH length 0 ");
+        assert_eq!(body(client.delete(uri.clone()).unwrap()), "DELETE none ");
+        assert_eq!(body(client.options(uri.clone()).unwrap()), "OPTIONS none ");
+        // the response of HEAD has no body
+        let rsp = client.head(uri.clone()).unwrap();
+        assert_eq!(rsp.headers()[CONTENT_LENGTH], "10");
+        assert_eq!(body(rsp), "");
+
+        // the body without a length is chunked
+        let method = Method::from_bytes(b"PURGE").unwrap();
+        let mut req = client.new_request(method, uri.clone());
+        req.write_all(b"hello ").unwrap();
+        req.write_all(b"world").unwrap();
+        let rsp = client.send_request(req).unwrap();
+        assert_eq!(body(rsp), "PURGE chunked hello world");
+        // the length can be set by the header
+        let mut req = client.new_request(Method::DELETE, uri);
+        req.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from_static("2"));
+        req.write_all(b"id").unwrap();
+        let rsp = client.send_request(req).unwrap();
+        assert_eq!(body(rsp), "DELETE length 2 id");
+        // the invalid length is reported instead of waiting for the response
+        let mut req = client.new_request(Method::POST, "/".parse().unwrap());
+        req.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from_static("x"));
+        let e = client.send_request(req).unwrap_err();
+        assert!(matches!(Error::from(e), Error::Parse(_)));
+        // nothing is sent, the connection is still usable
+        assert_eq!(body(client.get("/".parse().unwrap()).unwrap()), "GET none ");
+
+        server.shutdown(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn response_framing() {
+        script_server(
+            8107,
+            vec![
+                vec![
+                    b"HTTP/1.1 100 Continue\r\n\r\n\
+                      HTTP/1.1 204 No Content\r\nContent-Length: 3\r\n\r\n",
+                    b"HTTP/1.1 304 Not Modified\r\nTransfer-Encoding: chunked\r\n\r\n",
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n",
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+                ],
+                vec![b"HTTP/1.0 200 OK\r\n\r\nuntil the close"],
+                vec![b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\nraw"],
+            ],
+        );
+        let uri: Uri = "/".parse().unwrap();
+
+        // the responses without body don't block the following ones
+        let mut client = HttpClient::connect("127.0.0.1:8107").unwrap();
+        let rsp = client.get(uri.clone()).unwrap();
+        assert_eq!(rsp.status(), 204);
+        assert_eq!(body(rsp), "");
+        let rsp = client.get(uri.clone()).unwrap();
+        assert_eq!(rsp.status(), 304);
+        assert_eq!(body(rsp), "");
+        let rsp = client.head(uri.clone()).unwrap();
+        assert_eq!(body(rsp), "");
+        assert_eq!(body(client.get(uri.clone()).unwrap()), "ok");
+
+        // the body without a length is delimited by the close
+        let mut client = HttpClient::connect("127.0.0.1:8107").unwrap();
+        let rsp = client.get(uri.clone()).unwrap();
+        assert!(rsp.is_close_delimited());
+        assert_eq!(body(rsp), "until the close");
+
+        // so is the body with an unknown transfer coding
+        let client = HttpClient::builder().build();
+        let uri = "http://127.0.0.1:8107/".parse().unwrap();
+        let rsp = client.get(uri).unwrap();
+        assert!(rsp.is_close_delimited());
+        assert_eq!(body(rsp), "raw");
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn response_decompress() {
+        use crate::server::{Compression, HttpServer, ServiceBuilder};
+
+        let service = ServiceBuilder::new()
+            .layer(Compression::new().min_size(0))
+            .service(
+                |_req: crate::server::Request, rsp: &mut crate::server::Response| {
+                    rsp.headers_mut()
+                        .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+                    rsp.send(b"hello compressed world").unwrap();
+                },
+            );
+        let mut server = HttpServer::new(service);
+        server.set_stack_size(Some(0x10000));
+        let server = server.start("127.0.0.1:8108").unwrap();
+        let uri: Uri = "/".parse().unwrap();
+
+        // the body is decoded and the headers describe the decoded body
+        let mut client = HttpClient::connect("127.0.0.1:8108").unwrap();
+        let rsp = client.get(uri.clone()).unwrap();
+        assert!(!rsp.headers().contains_key(CONTENT_ENCODING));
+        assert!(!rsp.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(body(rsp), "hello compressed world");
+
+        // the raw body is kept without decompression
+        client.set_decompress(false);
+        let mut rsp = client.get(uri.clone()).unwrap();
+        assert!(rsp.headers().contains_key(CONTENT_ENCODING));
+        let mut raw = Vec::new();
+        rsp.read_to_end(&mut raw).unwrap();
+        assert_ne!(raw, b"hello compressed world");
+
+        // the client that doesn't accept any coding
+        client.default_headers_mut().remove(ACCEPT_ENCODING);
+        let rsp = client.get(uri).unwrap();
+        assert!(!rsp.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(body(rsp), "hello compressed world");
+
+        let client = HttpClient::builder().build();
+        let rsp = client.get("http://127.0.0.1:8108/".parse().unwrap());
+        assert_eq!(body(rsp.unwrap()), "hello compressed world");
+
+        server.shutdown(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn chunk_trailers() {
+        let server = crate::server::HttpServer::new(
+            |mut req: crate::server::Request, rsp: &mut crate::server::Response| {
+                let mut body = Vec::new();
+                req.read_to_end(&mut body).unwrap();
+                let sum = req.trailers()["x-sum"].clone();
+                rsp.trailers_mut().insert("x-sum", sum);
+                rsp.send(&body).unwrap();
+            },
+        )
+        .start("127.0.0.1:8110")
+        .unwrap();
+
+        let mut client = HttpClient::connect("127.0.0.1:8110").unwrap();
+        let mut req = client.new_request(Method::POST, "/".parse().unwrap());
+        req.trailers_mut()
+            .insert("x-sum", HeaderValue::from_static("42"));
+        req.send(b"data").unwrap();
+        let mut rsp = client.send_request(req).unwrap();
+        // the trailers force the chunked encoding
+        assert_eq!(rsp.headers()[TRAILER], "x-sum");
+        assert!(rsp.trailers().is_empty());
+        let mut body = Vec::new();
+        rsp.read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"data");
+        assert_eq!(rsp.trailers()["x-sum"], "42");
+
+        server.shutdown(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn request_headers() {
+        head_server(8106);
+        let mut client = HttpClient::connect("127.0.0.1:8106").unwrap();
+
+        let head = body(client.get("/a?b=1".parse().unwrap()).unwrap());
+        assert!(head.starts_with("GET /a?b=1 HTTP/1.1\r\n"));
+        assert!(head.contains("host: 127.0.0.1:8106\r\n"));
+        assert_eq!(head.matches("user-agent: may_http\r\n").count(), 1);
+        assert_eq!(head.matches("accept: */*\r\n").count(), 1);
+
+        // the absolute uri is sent in origin form with its authority as the host
+        let uri = "http://example.com:8080".parse().unwrap();
+        let head = body(client.get(uri).unwrap());
+        assert!(head.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(head.contains("host: example.com:8080\r\n"));
+        assert!(!head.contains("127.0.0.1"));
+
+        // the host is named instead of the resolved address
+        let mut named = HttpClient::connect_named("localhost", 8106).unwrap();
+        let head = body(named.get("/".parse().unwrap()).unwrap());
+        assert!(head.contains("host: localhost:8106\r\n"));
+
+        // the proxy form and the changed default headers
+        client.set_absolute_form(true);
+        client.default_headers_mut().remove(USER_AGENT);
+        let uri = "http://example.com/p".parse().unwrap();
+        let mut req = client.new_request(Method::GET, uri);
+        let value = HeaderValue::from_bytes(b"caf\xe9").unwrap();
+        req.headers_mut().insert("x-opaque", value);
+        let mut rsp = client.send_request(req).unwrap();
+        let mut head = Vec::new();
+        rsp.read_to_end(&mut head).unwrap();
+        assert!(head.starts_with(b"GET http://example.com/p HTTP/1.1\r\n"));
+        let opaque = b"x-opaque: caf\xe9\r\n";
+        assert!(head.windows(opaque.len()).any(|w| w == opaque));
+        let head = String::from_utf8_lossy(&head);
+        assert!(!head.contains("user-agent"));
+
+        // the pooled client merges the request headers into the defaults
+        let mut headers = default_headers();
+        headers.insert("x-default", HeaderValue::from_static("1"));
+        let client = HttpClient::builder().default_headers(headers).build();
+        let req = http::Request::get("http://127.0.0.1:8106/q")
+            .header(USER_AGENT, "test")
+            .body(&[][..])
+            .unwrap();
+        let head = body(client.request(req).unwrap());
+        assert!(head.starts_with("GET /q HTTP/1.1\r\n"));
+        assert!(head.contains("host: 127.0.0.1:8106\r\n"));
+        assert!(head.contains("user-agent: test\r\n"));
+        assert!(head.contains("x-default: 1\r\n"));
+        assert!(!head.contains("may_http"));
+    }
+
+    #[test]
+    fn request_timeout() {
+        trickle_server(8101);
+        let uri: Uri = "/".parse().unwrap();
+
+        // each read is within the io timeout, but the request is too slow
+        let mut client =
+            HttpClient::connect_timeout("127.0.0.1:8101", Duration::from_secs(1)).unwrap();
+        client
+            .set_timeout(Some(Duration::from_millis(150)))
+            .set_request_timeout(Some(Duration::from_millis(200)));
+        let now = Instant::now();
+        let mut rsp = client.get(uri.clone()).unwrap();
+        let e = rsp.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(Error::from(e), Error::Timeout));
+        assert!(now.elapsed() < Duration::from_millis(400));
+
+        // the request overrides the client timeout
+        let mut client = HttpClient::connect("127.0.0.1:8101").unwrap();
+        client
+            .set_timeout(Some(Duration::from_millis(150)))
+            .set_request_timeout(Some(Duration::from_millis(200)));
+        let mut req = client.new_request(Method::GET, uri);
+        req.set_timeout(None);
+        let mut rsp = client.send_request(req).unwrap();
+        let mut body = Vec::new();
+        rsp.read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"xxxxxxxxxx");
+
+        // the pooled client
+        let client = HttpClient::builder()
+            .connect_timeout(Duration::from_secs(1))
+            .timeout(Duration::from_millis(200))
+            .build();
+        let mut rsp = client
+            .get("http://127.0.0.1:8101/".parse().unwrap())
+            .unwrap();
+        let e = rsp.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(Error::from(e), Error::Timeout));
+    }
+}
@@ -1,7 +1,13 @@
 mod client_impl;
+mod pool;
 mod request;
 mod response;
+#[cfg(feature = "rustls")]
+mod tls;
 
 pub use self::client_impl::HttpClient;
+pub use self::pool::{ClientBuilder, PooledClient};
 pub use self::request::Request;
 pub use self::response::Response;
+#[cfg(feature = "rustls")]
+pub use self::tls::{TlsConnector, TlsConnectorBuilder};
@@ -0,0 +1,506 @@
+//! pooled http client that reuses the keep-alive connections
+//!
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes};
+use http::header::*;
+use http::{Method, Uri};
+use may::net::TcpStream;
+
+use crate::buffer::BufferIo;
+use crate::client::client_impl::{connect_tcp, default_headers, Io, Stream};
+#[cfg(feature = "rustls")]
+use crate::client::TlsConnector;
+use crate::client::{HttpClient, Response};
+use crate::limits::HeadLimits;
+use crate::Error;
+
+// the connections are pooled by scheme, host and port
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    tls: bool,
+    host: String,
+    port: u16,
+}
+
+impl Key {
+    fn from_uri(uri: &Uri) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let tls = match uri.scheme_str() {
+            Some("http") => false,
+            Some("https") => true,
+            Some(_) => return Err(invalid("unsupported uri scheme")),
+            None => return Err(invalid("uri without scheme")),
+        };
+        let host = uri.host().ok_or_else(|| invalid("uri without host"))?;
+        // the ipv6 host is wrapped with brackets in the uri
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+        Ok(Key {
+            tls,
+            host: host.to_owned(),
+            port,
+        })
+    }
+}
+
+// the idle connection and the time it's returned to the pool
+struct Idle {
+    since: Instant,
+    conn: BufferIo<Stream>,
+}
+
+/// the idle connections
+pub(crate) struct Pool {
+    idle: Mutex<HashMap<Key, Vec<Idle>>>,
+    max_idle_per_host: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl Pool {
+    // take the most recently used idle connection, the expired ones are evicted
+    fn take(&self, key: &Key) -> Option<BufferIo<Stream>> {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.get_mut(key)?;
+        if let Some(timeout) = self.idle_timeout {
+            conns.retain(|i| i.since.elapsed() < timeout);
+        }
+        let conn = conns.pop().map(|i| i.conn);
+        if conns.is_empty() {
+            idle.remove(key);
+        }
+        conn
+    }
+
+    // put the connection back to the pool
+    fn put(&self, key: Key, mut conn: BufferIo<Stream>) {
+        // the peer should not send anything before the next request
+        if self.max_idle_per_host == 0 || !conn.get_reader_buf().is_empty() {
+            return;
+        }
+        conn.inner_mut().set_deadline(None);
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry(key).or_default();
+        if conns.len() < self.max_idle_per_host {
+            conns.push(Idle {
+                since: Instant::now(),
+                conn,
+            });
+        }
+    }
+}
+
+/// the connection that is in use by a response
+///
+/// it's returned to the pool when the response is dropped
+pub(crate) struct Pooled {
+    conn: Rc<RefCell<BufferIo<Stream>>>,
+    pool: Arc<Pool>,
+    key: Key,
+}
+
+impl Pooled {
+    /// return the connection to the pool
+    pub(crate) fn release(self) {
+        if let Ok(conn) = Rc::try_unwrap(self.conn) {
+            self.pool.put(self.key, conn.into_inner());
+        }
+    }
+}
+
+/// builder for `PooledClient`
+pub struct ClientBuilder {
+    max_idle_per_host: usize,
+    idle_timeout: Option<Duration>,
+    limits: HeadLimits,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    headers: HeaderMap,
+    decompress: bool,
+    #[cfg(feature = "rustls")]
+    connector: Option<TlsConnector>,
+}
+
+impl ClientBuilder {
+    pub(crate) fn new() -> Self {
+        ClientBuilder {
+            max_idle_per_host: usize::MAX,
+            idle_timeout: Some(Duration::from_secs(90)),
+            limits: HeadLimits::default(),
+            connect_timeout: None,
+            timeout: None,
+            headers: default_headers(),
+            decompress: true,
+            #[cfg(feature = "rustls")]
+            connector: None,
+        }
+    }
+
+    /// set the maximum idle connections kept for each host
+    ///
+    /// default is unlimited, set it to 0 to disable the connection reuse
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.max_idle_per_host = max;
+        self
+    }
+
+    /// set how long an idle connection is kept in the pool
+    ///
+    /// default is 90 seconds, `None` means the idle connections never expire
+    pub fn pool_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// set the maximum number of response headers, default is 64
+    pub fn max_headers(mut self, max: usize) -> Self {
+        self.limits.max_headers = max;
+        self
+    }
+
+    /// set the maximum size of the response head, default is 64KiB
+    pub fn max_head_size(mut self, size: usize) -> Self {
+        self.limits.max_head_size = size;
+        self
+    }
+
+    /// set the timeout of establishing a new connection, default is `None`
+    ///
+    /// it covers both the tcp connect and the tls handshake
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// set the total timeout of each request, default is `None`
+    ///
+    /// the deadline covers getting the connection, sending the request
+    /// and receiving the response head and body, after that the io
+    /// fails with `Error::Timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// set the headers that added to each request
+    ///
+    /// they replace the defaults `User-Agent: may_http`, `Accept: */*` and
+    /// the `Accept-Encoding` of the supported codings, the headers of the
+    /// request take precedence over them
+    pub fn default_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// decode the compressed response body, default is true
+    ///
+    /// see `HttpClient::set_decompress`
+    pub fn decompress(mut self, decompress: bool) -> Self {
+        self.decompress = decompress;
+        self
+    }
+
+    /// set the tls connector that used for the `https` uris
+    ///
+    /// without a connector the `https` requests would fail
+    #[cfg(feature = "rustls")]
+    pub fn tls_connector(mut self, connector: TlsConnector) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// build the client
+    pub fn build(self) -> PooledClient {
+        let pool = Pool {
+            idle: Mutex::new(HashMap::new()),
+            max_idle_per_host: self.max_idle_per_host,
+            idle_timeout: self.idle_timeout,
+        };
+        PooledClient {
+            pool: Arc::new(pool),
+            limits: self.limits,
+            connect_timeout: self.connect_timeout,
+            timeout: self.timeout,
+            headers: self.headers,
+            decompress: self.decompress,
+            #[cfg(feature = "rustls")]
+            connector: self.connector,
+        }
+    }
+}
+
+/// http client that can send requests to any host
+///
+/// the keep-alive connections are kept in a pool and reused by the
+/// following requests to the same host. the client is cheap to clone
+/// and can be shared between coroutines
+#[derive(Clone)]
+pub struct PooledClient {
+    pool: Arc<Pool>,
+    limits: HeadLimits,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    headers: HeaderMap,
+    decompress: bool,
+    #[cfg(feature = "rustls")]
+    connector: Option<TlsConnector>,
+}
+
+impl fmt::Debug for PooledClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<PooledClient>")
+    }
+}
+
+impl PooledClient {
+    /// send a GET request to the absolute uri and return the response
+    pub fn get(&self, uri: Uri) -> io::Result<Response> {
+        let req = http::Request::get(uri).body(&[][..]);
+        self.request(req.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?)
+    }
+
+    /// send a POST request with the data to the absolute uri and return the response
+    pub fn post<T: Buf>(&self, uri: Uri, data: T) -> io::Result<Response> {
+        let req = http::Request::post(uri).body(data);
+        self.request(req.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?)
+    }
+
+    /// send the request to the absolute uri and return the response
+    ///
+    /// the connection is returned to the pool after the response is dropped,
+    /// the remaining body would be consumed at that time
+    ///
+    /// if the reused idle connection turns out closed by the server, the
+    /// request is sent again on a new connection only for the idempotent
+    /// methods, otherwise the error is returned
+    pub fn request<T: Buf>(&self, req: http::Request<T>) -> io::Result<Response> {
+        // collect the whole body, it may be sent again on a new connection
+        let req = req.map(|mut body| body.to_bytes());
+        let key = Key::from_uri(req.uri())?;
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        if let Some(conn) = self.pool.take(&key) {
+            match self.send(&key, conn, &req, deadline) {
+                Ok(rsp) => return Ok(rsp),
+                // the idle connection may be closed by the peer, try a new one,
+                // the other requests may be already processed by the server
+                Err(ref e) if is_closed(e) && is_idempotent(req.method()) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let conn = self.connect(&key, deadline)?;
+        self.send(&key, conn, &req, deadline)
+    }
+
+    // create a new connection to the host
+    fn connect(&self, key: &Key, deadline: Option<Instant>) -> io::Result<BufferIo<Stream>> {
+        let left = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        let timeout = match (self.connect_timeout, left) {
+            (Some(t), Some(left)) => Some(t.min(left)),
+            (t, left) => t.or(left),
+        };
+        if timeout == Some(Duration::from_secs(0)) {
+            return Err(Error::Timeout.into());
+        }
+        // the tls handshake shares the timeout with the tcp connect
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let stream = connect_tcp((key.host.as_str(), key.port), timeout)?;
+        let io = if key.tls {
+            self.connect_tls(key, stream, deadline)?
+        } else {
+            Io::Tcp(stream)
+        };
+        Ok(BufferIo::new(Stream::new(io)))
+    }
+
+    #[cfg(feature = "rustls")]
+    fn connect_tls(
+        &self,
+        key: &Key,
+        stream: TcpStream,
+        deadline: Option<Instant>,
+    ) -> io::Result<Io> {
+        let connector = self.connector.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no tls connector for https uri",
+            )
+        })?;
+        let stream = connector.connect(&key.host, stream, deadline)?;
+        Ok(Io::Tls(Box::new(stream)))
+    }
+
+    #[cfg(not(feature = "rustls"))]
+    fn connect_tls(
+        &self,
+        _key: &Key,
+        _stream: TcpStream,
+        _deadline: Option<Instant>,
+    ) -> io::Result<Io> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "https is not supported without the rustls feature",
+        ))
+    }
+
+    // send the request over the connection
+    fn send(
+        &self,
+        key: &Key,
+        conn: BufferIo<Stream>,
+        req: &http::Request<Bytes>,
+        deadline: Option<Instant>,
+    ) -> io::Result<Response> {
+        // the rest of the request deadline
+        let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        let mut client = HttpClient::from_conn(conn, self.limits, timeout, self.headers.clone());
+        client.set_decompress(self.decompress);
+        // the host is taken from the uri and the origin form is sent to the server
+        let mut r = client.new_request(req.method().clone(), req.uri().clone());
+        *r.version_mut() = req.version();
+        let headers = r.headers_mut();
+        for key in req.headers().keys() {
+            headers.remove(key);
+            for value in req.headers().get_all(key) {
+                headers.append(key, value.clone());
+            }
+        }
+        let body = req.body();
+        if !body.is_empty() {
+            r.send(body)?;
+        }
+        let mut rsp = client.send_request(r)?;
+        rsp.set_pooled(Pooled {
+            conn: client.into_conn(),
+            pool: self.pool.clone(),
+            key: key.clone(),
+        });
+        Ok(rsp)
+    }
+}
+
+// check if the error is caused by a closed connection
+fn is_closed(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+// check if the request can be sent again safely
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{HttpServer, Request, Response};
+    use bytes::buf::BufExt;
+    use std::io::Read;
+
+    // reply the peer address, so that we know which connection is used
+    fn peer(mut req: Request, rsp: &mut Response) {
+        if req.uri().path() == "/body" {
+            let mut body = Vec::new();
+            req.read_to_end(&mut body).unwrap();
+            return rsp.send(&body).unwrap();
+        }
+        let close = req.uri().path() == "/close";
+        if close {
+            rsp.headers_mut()
+                .insert(CONNECTION, "close".parse().unwrap());
+        }
+        rsp.send(req.uri().path().as_bytes()).unwrap();
+    }
+
+    fn get(client: &PooledClient, uri: &str) -> String {
+        let mut rsp = client.get(uri.parse().unwrap()).unwrap();
+        let mut s = String::new();
+        rsp.read_to_string(&mut s).unwrap();
+        s
+    }
+
+    fn idle_count(client: &PooledClient) -> usize {
+        let idle = client.pool.idle.lock().unwrap();
+        idle.values().map(|v| v.len()).sum()
+    }
+
+    #[test]
+    fn pooled_client() {
+        let server = HttpServer::new(peer).start("127.0.0.1:8095").unwrap();
+        let client = HttpClient::builder().pool_max_idle_per_host(1).build();
+
+        assert_eq!(get(&client, "http://127.0.0.1:8095/a"), "/a");
+        assert_eq!(idle_count(&client), 1);
+        assert_eq!(get(&client, "http://127.0.0.1:8095/b?x=1"), "/b");
+        assert_eq!(idle_count(&client), 1);
+
+        // the connection is not returned while the response is alive
+        let uri: Uri = "http://127.0.0.1:8095/c".parse().unwrap();
+        let rsp1 = client.get(uri.clone()).unwrap();
+        let rsp2 = client.post(uri, &b"data"[..]).unwrap();
+        assert_eq!(idle_count(&client), 0);
+        // the body is consumed when dropped
+        drop(rsp1);
+        drop(rsp2);
+        // only one idle connection is kept
+        assert_eq!(idle_count(&client), 1);
+
+        // the body with several chunks is sent in whole
+        let uri: Uri = "http://127.0.0.1:8095/body".parse().unwrap();
+        let mut rsp = client
+            .post(uri, BufExt::chain(&b"hello "[..], &b"world"[..]))
+            .unwrap();
+        let mut s = String::new();
+        rsp.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "hello world");
+        drop(rsp);
+
+        // the connection closed by the server is not reused
+        assert_eq!(get(&client, "http://127.0.0.1:8095/close"), "/close");
+        assert_eq!(idle_count(&client), 0);
+
+        // the client can be shared between coroutines
+        let c = client.clone();
+        let h = may::go!(move || get(&c, "http://127.0.0.1:8095/d"));
+        assert_eq!(h.join().unwrap(), "/d");
+        assert_eq!(idle_count(&client), 1);
+
+        // the idle connection closed by the server is replaced
+        let ret = server.shutdown(Duration::from_secs(1));
+        assert_eq!(ret, 0);
+        let server = HttpServer::new(peer).start("127.0.0.1:8095").unwrap();
+        assert_eq!(get(&client, "http://127.0.0.1:8095/e"), "/e");
+
+        // the POST request is not sent again on the closed connection
+        server.shutdown(Duration::from_secs(1));
+        let server = HttpServer::new(peer).start("127.0.0.1:8095").unwrap();
+        let uri: Uri = "http://127.0.0.1:8095/e".parse().unwrap();
+        assert!(client.post(uri.clone(), &b"data"[..]).is_err());
+        assert!(client.post(uri, &b"data"[..]).is_ok());
+
+        // the expired connections are evicted
+        let client = HttpClient::builder()
+            .pool_idle_timeout(Some(Duration::from_millis(0)))
+            .build();
+        get(&client, "http://127.0.0.1:8095/f");
+        assert_eq!(idle_count(&client), 1);
+        let key = Key::from_uri(&"http://127.0.0.1:8095/".parse().unwrap()).unwrap();
+        assert!(client.pool.take(&key).is_none());
+
+        assert!(client.get("/no_host".parse().unwrap()).is_err());
+        server.shutdown(Duration::from_secs(1));
+    }
+}
@@ -2,15 +2,18 @@
 //!
 //! These are Requests sent by a `may_http::Server` to clients, after
 //! receiving a request.
-use std::cell::RefCell;
+use std::cell::{RefCell, RefMut};
 use std::fmt;
 use std::io::{self, Write};
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-// use http::header::*;
-use crate::body::BodyWriter;
-use http::{self, Method};
+use crate::body::{content_length, trailer_names, BodyWriter};
+use crate::buffer::BufferIo;
+use crate::client::client_impl::Stream;
+use http::header::*;
+use http::{self, Method, Version};
 
 /// The outgoing half for a Stream, created by a `Client` and given to a `HttpClient`.
 ///
@@ -27,6 +30,12 @@ pub struct Request {
     writer: Rc<RefCell<dyn Write>>,
     // the cached Request size
     body_size: Option<usize>,
+    // the client connection that the deadline is applied to
+    stream: Option<Rc<RefCell<BufferIo<Stream>>>>,
+    // write the absolute uri in the request line
+    absolute_form: bool,
+    // the trailer fields that written after the chunked body
+    trailers: Rc<RefCell<HeaderMap>>,
 }
 
 impl fmt::Debug for Request {
@@ -43,51 +52,105 @@ impl Request {
             raw_req: http::Request::new(BodyWriter::InvalidWriter),
             writer: stream,
             body_size: None,
+            stream: None,
+            absolute_form: false,
+            trailers: Rc::new(RefCell::new(HeaderMap::new())),
+        }
+    }
+
+    // the request-target of the request line
+    fn target(&self) -> String {
+        let uri = self.uri();
+        if *self.method() == Method::CONNECT {
+            if let Some(authority) = uri.authority() {
+                return authority.to_string();
+            }
+        }
+        if self.absolute_form && uri.scheme().is_some() {
+            return uri.to_string();
+        }
+        match uri.path_and_query() {
+            Some(p) if !p.as_str().is_empty() => p.to_string(),
+            _ => if *self.method() == Method::OPTIONS {
+                "*"
+            } else {
+                "/"
+            }
+            .to_owned(),
         }
     }
 
     // actual write head to stream
-    fn write_head_impl(&mut self) -> io::Result<()> {
+    fn write_head_impl(&mut self, size: Option<usize>, chunked: bool) -> io::Result<()> {
         let mut writer = self.writer.borrow_mut();
 
         write!(
             writer,
             "{} {} {:?}\r\n",
             self.method(),
-            self.uri(),
+            self.target(),
             self.version()
         )?;
-        // TODO: check server header
-        write!(writer, "User-Agent: may_http\r\nAccept: */*\r\n")?;
+
+        // http/1.1 requires the host header, it's empty if the uri has no authority
+        if !self.headers().contains_key(HOST) && self.version() == Version::HTTP_11 {
+            let host = self.uri().authority().map_or("", |a| a.as_str());
+            write!(writer, "Host: {}\r\n", host)?;
+        }
 
         for (key, value) in self.headers().iter() {
-            write!(
-                writer,
-                "{}: {}\r\n",
-                key.as_str(),
-                value.to_str().unwrap_or("")
-            )?;
+            // the body framing headers are written below
+            if key == CONTENT_LENGTH || key == TRANSFER_ENCODING {
+                continue;
+            }
+            // the value may contain opaque bytes
+            write!(writer, "{}: ", key.as_str())?;
+            writer.write_all(value.as_bytes())?;
+            writer.write_all(b"\r\n")?;
         }
 
-        if let Some(len) = self.body_size {
+        if let Some(len) = size {
             write!(writer, "Content-Length: {}\r\n", len)?
+        } else if chunked {
+            write!(writer, "Transfer-Encoding: chunked\r\n")?
         }
 
         write!(writer, "\r\n")?;
         Ok(())
     }
 
-    // write head to stream
-    fn write_head(&mut self) -> io::Result<BodyWriter> {
-        let body = match *self.method() {
-            Method::GET | Method::HEAD => BodyWriter::EmptyWriter(self.writer.clone()),
-            Method::POST => match self.body_size {
-                Some(size) => BodyWriter::SizedWriter(self.writer.clone(), size),
-                None => BodyWriter::ChunkWriter(self.writer.clone()),
-            },
-            _ => unimplemented!(),
+    // write head to stream, `has_body` is false if the request is dropped
+    // without writing anything
+    fn write_head(&mut self, has_body: bool) -> io::Result<BodyWriter> {
+        let mut size = match self.body_size {
+            Some(size) => Some(size),
+            None => content_length(self.headers())?,
+        };
+        let names = trailer_names(&self.trailers.borrow());
+        let has_trailers = names.is_some();
+        if let Some(names) = names {
+            // only the chunked body can carry the trailers
+            self.headers_mut().insert(TRAILER, names);
+            size = None;
+        }
+        let writer = self.writer.clone();
+        let (body, size) = match size {
+            Some(size) => (BodyWriter::SizedWriter(writer, size), Some(size)),
+            None if has_body || has_trailers => {
+                (BodyWriter::ChunkWriter(writer, self.trailers.clone()), None)
+            }
+            None => {
+                // send an explicit zero length for the methods that expect a body
+                let expect_body =
+                    matches!(*self.method(), Method::POST | Method::PUT | Method::PATCH);
+                (
+                    BodyWriter::EmptyWriter(writer),
+                    Some(0).filter(|_| expect_body),
+                )
+            }
         };
-        self.write_head_impl()?;
+        let chunked = matches!(body, BodyWriter::ChunkWriter(..));
+        self.write_head_impl(size, chunked)?;
         Ok(body)
     }
 
@@ -132,11 +195,74 @@ impl Request {
         self.body_size = Some(len);
     }
 
-    /// get the connection
+    /// get the trailer fields that sent after the body
+    ///
+    /// the trailers set before writing the body are announced by the
+    /// `Trailer` header and the body is sent with the chunked encoding.
+    /// they can also be set after that until the request is sent, but
+    /// they are dropped if the body is not chunked
+    pub fn trailers_mut(&mut self) -> RefMut<'_, HeaderMap> {
+        self.trailers.borrow_mut()
+    }
+
+    /// set the trailer fields that sent after the body, see `trailers_mut`
+    pub fn set_trailers(&mut self, trailers: HeaderMap) {
+        *self.trailers.borrow_mut() = trailers;
+    }
+
+    /// set the total timeout of the request, `None` means no timeout
+    ///
+    /// this overrides the `HttpClient` setting, the deadline starts now
+    /// and covers sending the request and receiving the response. it
+    /// only works for the request created by the `HttpClient`
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        if let Some(ref stream) = self.stream {
+            let deadline = timeout.map(|t| Instant::now() + t);
+            stream.borrow_mut().inner_mut().set_deadline(deadline);
+        }
+    }
 
+    /// write the absolute uri in the request line, default is false
+    ///
+    /// otherwise only the path and query of the uri are written. the
+    /// `CONNECT` request always writes the authority of the uri
+    pub fn set_absolute_form(&mut self, absolute: bool) {
+        self.absolute_form = absolute;
+    }
+
+    // set the client connection
+    pub(super) fn set_stream(&mut self, stream: Rc<RefCell<BufferIo<Stream>>>) {
+        self.stream = Some(stream);
+    }
+
+    /// get the connection
     pub(super) fn conn(&self) -> &Rc<RefCell<dyn Write>> {
         &self.writer
     }
+
+    /// write the head if it's not written yet, the body is finished
+    ///
+    /// a request that can't be written is discarded and the error is
+    /// returned, so that no response is waited for it
+    pub(super) fn finish(&mut self) -> io::Result<()> {
+        if let BodyWriter::InvalidWriter = *self.body() {
+            match self.write_head(false) {
+                Ok(body) => *self.body_mut() = body,
+                Err(e) => {
+                    self.discard();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// drop the request without writing the head
+    pub(super) fn discard(&mut self) {
+        if let BodyWriter::InvalidWriter = *self.body() {
+            *self.body_mut() = BodyWriter::EmptyWriter(self.writer.clone());
+        }
+    }
 }
 
 impl Deref for Request {
@@ -161,7 +287,7 @@ impl Write for Request {
     #[inline]
     fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
         if let BodyWriter::InvalidWriter = *self.body() {
-            *self.body_mut() = self.write_head()?;
+            *self.body_mut() = self.write_head(true)?;
         }
         self.body_mut().write(msg)
     }
@@ -181,11 +307,9 @@ impl Drop for Request {
             return;
         }
 
-        // make sure we write every thing
-        if let BodyWriter::InvalidWriter = *self.body() {
-            *self.body_mut() = self
-                .write_head()
-                .unwrap_or_else(|_| BodyWriter::EmptyWriter(self.writer.clone()));
-        }
+        // make sure we write every thing, the client sends the request
+        // by `finish` that reports the error, a dropped request has no
+        // one to report to
+        self.finish().ok();
     }
 }
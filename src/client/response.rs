@@ -1,36 +1,54 @@
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
 use std::fmt;
 use std::io::{self, Read};
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 
-use crate::body::BodyReader;
+use crate::body::coding::content_codings;
+use crate::body::{content_length, is_chunked, BodyReader, ChunkInfo};
+use crate::client::pool::Pooled;
+use crate::limits::HeadLimits;
+use crate::Error;
 use bytes::{Bytes, BytesMut};
 use http::header::*;
-use http::{self, Version};
+use http::{self, Method, StatusCode, Version};
 use httparse;
 
-pub(crate) fn decode(buf: &mut BytesMut) -> io::Result<Option<Response>> {
+pub(crate) fn decode(buf: &mut BytesMut, limits: &HeadLimits) -> io::Result<Option<Response>> {
     #[inline]
     fn get_slice(buf: &Bytes, data: &[u8]) -> Bytes {
         let begin = data.as_ptr() as usize - buf.as_ptr() as usize;
         buf.slice(begin..begin + data.len())
     }
 
-    let mut headers: [httparse::Header; 64] =
-        unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-    let mut r = httparse::Response::new(&mut headers);
-    let status = r.parse(buf).map_err(|e| {
-        let msg = format!("failed to parse http Response: {:?}", e);
-        io::Error::new(io::ErrorKind::Other, msg)
+    // avoid the allocation for the common case
+    let mut stack_headers = [httparse::EMPTY_HEADER; 64];
+    let mut heap_headers = Vec::new();
+    let headers = if limits.max_headers <= stack_headers.len() {
+        &mut stack_headers[..limits.max_headers]
+    } else {
+        heap_headers.resize(limits.max_headers, httparse::EMPTY_HEADER);
+        &mut heap_headers[..]
+    };
+    // the parsed slices point into the buffer memory that would be split
+    // and frozen below, so detach the lifetime from the `buf` borrow
+    let data = unsafe { std::slice::from_raw_parts(buf.as_ptr(), buf.len()) };
+    let mut r = httparse::Response::new(headers);
+    let status = r.parse(data).map_err(|e| match e {
+        httparse::Error::TooManyHeaders => Error::HeadTooLarge,
+        e => Error::Parse(format!("failed to parse http Response: {:?}", e)),
     })?;
 
+    let amt = match status {
+        httparse::Status::Complete(amt) => amt,
+        httparse::Status::Partial => data.len(),
+    };
+    // don't buffer the endless head
+    if amt > limits.max_head_size {
+        return Err(Error::HeadTooLarge.into());
+    }
     let bytes = match status {
-        httparse::Status::Complete(amt) => {
-            #[allow(clippy::cast_ref_to_mut)]
-            let buf = unsafe { &mut *(buf as *const _ as *mut BytesMut) };
-            buf.split_to(amt).freeze()
-        }
+        httparse::Status::Complete(amt) => buf.split_to(amt).freeze(),
         httparse::Status::Partial => return Ok(None),
     };
 
@@ -58,38 +76,147 @@ pub(crate) fn decode(buf: &mut BytesMut) -> io::Result<Option<Response>> {
         rsp_builder = rsp_builder.header(header.name, value);
     }
 
-    rsp_builder
-        .body(BodyReader::EmptyReader)
-        .map(|req| Some(Response(req)))
-        .map_err(|e| {
-            let msg = format!("failed to build http Response: {:?}", e);
-            io::Error::new(io::ErrorKind::Other, msg)
-        })
+    let rsp = rsp_builder.body(BodyReader::EmptyReader).map_err(|e| {
+        let msg = format!("failed to build http Response: {:?}", e);
+        Error::Parse(msg)
+    })?;
+    // the transfer encoding overrides the content length
+    if !rsp.headers().contains_key(TRANSFER_ENCODING) {
+        content_length(rsp.headers())?;
+    }
+    Ok(Some(Response {
+        inner: rsp,
+        pooled: None,
+        close_delimited: false,
+        chunk: Rc::new(RefCell::new(ChunkInfo::default())),
+    }))
 }
 
 /// http server Response
 /// a thin wraper to http::Response
 /// impl Read for reading http Response body
-pub struct Response(http::Response<BodyReader>);
+pub struct Response {
+    inner: http::Response<BodyReader>,
+    // the pooled connection that would be released on drop
+    pooled: Option<Pooled>,
+    // the body ends with the connection, which can't be reused
+    close_delimited: bool,
+    // the extensions and the trailers of the chunked body
+    chunk: Rc<RefCell<ChunkInfo>>,
+}
 
 impl Response {
     // set the body reader
     // this function would be called by the client to
     // set a proper `BodyReader` according to the Response
-    pub(crate) fn set_reader(&mut self, reader: Rc<RefCell<dyn Read>>) {
-        use std::str;
+    // the framing follows RFC 7230 section 3.3.3
+    pub(crate) fn set_reader(
+        &mut self,
+        reader: Rc<RefCell<dyn Read>>,
+        method: &Method,
+        decompress: bool,
+    ) {
+        let status = self.status();
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            // the 101 connection is taken over by the upgrade protocol
+            return;
+        }
 
-        let size = self.headers().get(CONTENT_LENGTH).map(|v| {
-            let s = unsafe { str::from_utf8_unchecked(v.as_bytes()) };
-            s.parse().expect("failed to parse content length")
-        });
+        if *method == Method::HEAD {
+            // the headers describe the body that would be sent for a GET
+            return;
+        }
 
-        let body_reader = match size {
-            Some(n) => BodyReader::SizedReader(reader, n),
-            None => BodyReader::ChunkReader(reader, None),
+        if *method == Method::CONNECT && status.is_success() {
+            // the connection becomes a tunnel
+            return;
+        }
+
+        // the framing headers are already validated by `decode`
+        let body_reader = if self.headers().contains_key(TRANSFER_ENCODING) {
+            if is_chunked(self.headers()) {
+                BodyReader::ChunkReader(reader, None, self.chunk.clone())
+            } else {
+                self.close_delimited = true;
+                BodyReader::CloseReader(reader, false)
+            }
+        } else {
+            match content_length(self.headers()).unwrap_or_default() {
+                Some(n) => BodyReader::SizedReader(reader, n),
+                None => {
+                    self.close_delimited = true;
+                    BodyReader::CloseReader(reader, false)
+                }
+            }
         };
 
         *self.body_mut() = body_reader;
+        if decompress {
+            self.decode_body();
+        }
+    }
+
+    // decode the compressed body, the unsupported codings are left as is
+    // the headers are changed to describe the decoded body
+    fn decode_body(&mut self) {
+        let codings = match content_codings(self.headers()) {
+            Some(codings) if !codings.is_empty() => codings,
+            _ => return,
+        };
+        self.body_mut().decode(&codings);
+        let headers = self.headers_mut();
+        headers.remove(CONTENT_ENCODING);
+        headers.remove(CONTENT_LENGTH);
+    }
+
+    /// get the trailer fields of the chunked body
+    ///
+    /// it's empty until the body is read to the end
+    pub fn trailers(&self) -> Ref<'_, HeaderMap> {
+        Ref::map(self.chunk.borrow(), ChunkInfo::trailers)
+    }
+
+    /// get the `name[=value]` extensions of the last read chunk
+    ///
+    /// each read returns the data of at most one chunk, so they can be
+    /// checked after each read of the chunked body
+    pub fn chunk_extensions(&self) -> Ref<'_, [(String, Option<String>)]> {
+        Ref::map(self.chunk.borrow(), ChunkInfo::extensions)
+    }
+
+    /// the body is delimited by the connection close
+    ///
+    /// the connection can't be used by another request after that
+    pub fn is_close_delimited(&self) -> bool {
+        self.close_delimited
+    }
+
+    // set the pooled connection that the response is read from
+    pub(crate) fn set_pooled(&mut self, pooled: Pooled) {
+        self.pooled = Some(pooled);
+    }
+}
+
+impl Drop for Response {
+    fn drop(&mut self) {
+        use crate::server::should_keep_alive;
+
+        if let Some(pooled) = self.pooled.take() {
+            if self.close_delimited {
+                return;
+            }
+            // consume the remaining body so that the connection can be reused
+            let done = io::copy(self.body_mut(), &mut io::sink()).is_ok();
+            *self.body_mut() = BodyReader::EmptyReader;
+            let reusable = self.status() != StatusCode::SWITCHING_PROTOCOLS
+                && should_keep_alive(self.version(), self.headers());
+            if done && reusable {
+                pooled.release();
+            }
+        }
     }
 }
 
@@ -99,7 +226,7 @@ impl Deref for Response {
     /// deref to the http::Response
     #[inline]
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.inner
     }
 }
 
@@ -107,7 +234,7 @@ impl DerefMut for Response {
     /// deref_mut to the http::Response
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.inner
     }
 }
 
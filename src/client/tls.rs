@@ -0,0 +1,265 @@
+//! tls connector for the http client on top of `rustls`
+//!
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Instant;
+
+use may::net::TcpStream;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, StreamOwned};
+use rustls::{Error, SignatureScheme};
+
+/// the tls stream that used by the client
+pub(crate) type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+fn tls_err<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    crate::Error::Tls(e.into()).into()
+}
+
+/// the tls connector that used to create `https` connections
+///
+/// the connector is cheap to clone, and the tls session cache is
+/// shared between all the connections created from the same connector
+#[derive(Clone)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+}
+
+impl fmt::Debug for TlsConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<TlsConnector>")
+    }
+}
+
+impl TlsConnector {
+    /// create a builder to configure the connector
+    pub fn builder() -> TlsConnectorBuilder {
+        TlsConnectorBuilder {
+            roots: RootCertStore::empty(),
+            identity: None,
+            accept_invalid_certs: false,
+        }
+    }
+
+    /// create a connector with a customized tls configuration
+    pub fn with_config(config: Arc<ClientConfig>) -> Self {
+        TlsConnector { config }
+    }
+
+    /// do the tls handshake on the stream, `host` is used for SNI and
+    /// the server certificate verification
+    ///
+    /// the handshake fails with `Error::Timeout` after the deadline
+    pub(crate) fn connect(
+        &self,
+        host: &str,
+        mut stream: TcpStream,
+        deadline: Option<Instant>,
+    ) -> io::Result<TlsStream> {
+        let name = ServerName::try_from(host.to_owned()).map_err(tls_err)?;
+        let mut conn = ClientConnection::new(self.config.clone(), name).map_err(tls_err)?;
+        while conn.is_handshaking() {
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(crate::Error::Timeout.into());
+                }
+                stream.set_read_timeout(Some(left))?;
+                stream.set_write_timeout(Some(left))?;
+            }
+            conn.complete_io(&mut stream)?;
+        }
+        if deadline.is_some() {
+            stream.set_read_timeout(None)?;
+            stream.set_write_timeout(None)?;
+        }
+        Ok(StreamOwned::new(conn, stream))
+    }
+}
+
+/// builder for `TlsConnector`
+pub struct TlsConnectorBuilder {
+    roots: RootCertStore,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    accept_invalid_certs: bool,
+}
+
+impl TlsConnectorBuilder {
+    /// add a trusted root certificate
+    pub fn add_root_certificate(mut self, cert: CertificateDer<'_>) -> io::Result<Self> {
+        self.roots.add(cert).map_err(tls_err)?;
+        Ok(self)
+    }
+
+    /// replace the trusted root certificates with the given store
+    pub fn root_store(mut self, roots: RootCertStore) -> Self {
+        self.roots = roots;
+        self
+    }
+
+    /// set the client certificate chain and private key for mutual tls
+    pub fn identity(
+        mut self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Self {
+        self.identity = Some((cert_chain, key));
+        self
+    }
+
+    /// don't verify the server certificate
+    ///
+    /// this is dangerous and should only be used for testing
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// build the connector
+    pub fn build(self) -> io::Result<TlsConnector> {
+        let builder = ClientConfig::builder();
+        let builder = if self.accept_invalid_certs {
+            let provider = crypto::ring::default_provider();
+            let verifier = NoVerifier(provider.signature_verification_algorithms);
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+        } else {
+            builder.with_root_certificates(self.roots)
+        };
+        let config = match self.identity {
+            Some((cert_chain, key)) => builder
+                .with_client_auth_cert(cert_chain, key)
+                .map_err(tls_err)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsConnector::with_config(Arc::new(config)))
+    }
+}
+
+// accept any server certificate, but still check the handshake signatures
+#[derive(Debug)]
+struct NoVerifier(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::HttpClient;
+    use crate::server::{HttpsServer, Request, Response};
+    use http::Uri;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use std::io::Read;
+    use std::time::Duration;
+
+    fn hello(_req: Request, rsp: &mut Response) {
+        rsp.send(b"Hello Tls Client!").unwrap();
+    }
+
+    #[test]
+    fn https_client_get() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_der = cert.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let server = HttpsServer::new(hello, vec![cert_der.clone()], key.into()).unwrap();
+        let server = server.start("127.0.0.1:8444").unwrap();
+
+        let uri: Uri = "https://localhost:8444/".parse().unwrap();
+        let connector = TlsConnector::builder()
+            .add_root_certificate(cert_der)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut client = HttpClient::connect_https(&uri, &connector).unwrap();
+        let mut s = String::new();
+        for _ in 0..3 {
+            let mut rsp = client.get(uri.clone()).unwrap();
+            rsp.read_to_string(&mut s).unwrap();
+            assert_eq!(s, "Hello Tls Client!");
+            s.clear();
+        }
+
+        // the untrusted certificate is rejected by default
+        let connector = TlsConnector::builder().build().unwrap();
+        assert!(HttpClient::connect_https(&uri, &connector).is_err());
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        assert!(HttpClient::connect_https(&uri, &connector).is_ok());
+
+        // https uri can't be sent over a plain connection
+        let mut client = HttpClient::connect("127.0.0.1:8444").unwrap();
+        assert!(client.get(uri).is_err());
+
+        unsafe { server.coroutine().cancel() };
+        server.join().ok();
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        // the server accepts the connections but never answers the handshake
+        let listener = std::net::TcpListener::bind("127.0.0.1:8114").unwrap();
+        let t = std::thread::spawn(move || {
+            let conns: Vec<_> = listener.incoming().take(2).collect();
+            std::thread::sleep(Duration::from_secs(1));
+            drop(conns);
+        });
+        let connector = TlsConnector::builder().build().unwrap();
+        let uri: Uri = "https://127.0.0.1:8114/".parse().unwrap();
+        let timeout = Duration::from_millis(200);
+        let now = Instant::now();
+
+        let e = HttpClient::connect_https_timeout(&uri, &connector, timeout).unwrap_err();
+        assert!(matches!(crate::Error::from(e), crate::Error::Timeout));
+        let client = HttpClient::builder()
+            .connect_timeout(timeout)
+            .tls_connector(connector)
+            .build();
+        let e = client.get(uri).unwrap_err();
+        assert!(matches!(crate::Error::from(e), crate::Error::Timeout));
+        assert!(now.elapsed() < Duration::from_secs(1));
+        t.join().unwrap();
+    }
+}
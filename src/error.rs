@@ -0,0 +1,156 @@
+//! the error type of may_http
+//!
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// the error of may_http
+///
+/// the apis return `io::Result` to fit the `Read` and `Write` traits, the
+/// errors that produced by may_http itself are wrapped in the `io::Error`,
+/// use `Error::from` to get them back
+///
+/// ```no_run
+/// use may_http::client::HttpClient;
+/// use may_http::Error;
+///
+/// let mut client = HttpClient::connect("127.0.0.1:8080").unwrap();
+/// match client.get("/".parse().unwrap()) {
+///     Ok(rsp) => println!("{}", rsp.status()),
+///     Err(e) => match Error::from(e) {
+///         Error::ConnectionClosed | Error::Timeout => println!("retry"),
+///         e => println!("failed: {}", e),
+///     },
+/// }
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// the message head, the framing headers or the websocket frame
+    /// are malformed
+    Parse(String),
+    /// the message head exceeds the header count or size limit
+    HeadTooLarge,
+    /// the request uri exceeds the length limit
+    UriTooLong,
+    /// the http version is not supported
+    UnsupportedVersion,
+    /// the chunked body is malformed
+    InvalidChunk(String),
+    /// the body exceeds the size limit
+    BodyTooLarge,
+    /// the io operation timed out
+    Timeout,
+    /// the connection is closed by the peer
+    ConnectionClosed,
+    /// the tls handshake or the tls record is invalid
+    Tls(Box<dyn StdError + Send + Sync>),
+    /// other io error
+    Io(io::Error),
+}
+
+impl Error {
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Parse(_)
+            | Error::HeadTooLarge
+            | Error::UriTooLong
+            | Error::UnsupportedVersion
+            | Error::InvalidChunk(_)
+            | Error::BodyTooLarge
+            | Error::Tls(_) => io::ErrorKind::InvalidData,
+            Error::Timeout => io::ErrorKind::TimedOut,
+            Error::ConnectionClosed => io::ErrorKind::UnexpectedEof,
+            Error::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(msg) => write!(f, "parse error: {}", msg),
+            Error::HeadTooLarge => write!(f, "message head too large"),
+            Error::UriTooLong => write!(f, "uri too long"),
+            Error::UnsupportedVersion => write!(f, "unsupported http version"),
+            Error::InvalidChunk(msg) => write!(f, "invalid chunk: {}", msg),
+            Error::BodyTooLarge => write!(f, "body too large"),
+            Error::Timeout => write!(f, "timed out"),
+            Error::ConnectionClosed => write!(f, "connection closed"),
+            Error::Tls(e) => write!(f, "tls error: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Tls(e) => Some(&**e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        let is_tls = match e.get_ref() {
+            Some(inner) if inner.is::<Error>() => {
+                let inner = e.into_inner().unwrap();
+                return *inner.downcast::<Error>().unwrap();
+            }
+            #[cfg(feature = "rustls")]
+            Some(inner) => inner.is::<rustls::Error>(),
+            _ => false,
+        };
+        if is_tls {
+            return Error::Tls(e.into_inner().unwrap());
+        }
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::Timeout,
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Error::ConnectionClosed,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_error_round_trip() {
+        let e: io::Error = Error::BodyTooLarge.into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(Error::from(e), Error::BodyTooLarge));
+
+        let e: io::Error = Error::HeadTooLarge.into();
+        assert!(matches!(Error::from(e), Error::HeadTooLarge));
+
+        let e: io::Error = Error::Parse("bad".into()).into();
+        assert!(matches!(Error::from(e), Error::Parse(ref m) if m == "bad"));
+
+        let e = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(matches!(Error::from(e), Error::ConnectionClosed));
+        let e = io::Error::from(io::ErrorKind::TimedOut);
+        assert!(matches!(Error::from(e), Error::Timeout));
+
+        let e = io::Error::other("other");
+        let e: io::Error = Error::from(e).into();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert_eq!(e.to_string(), "other");
+    }
+}
@@ -5,7 +5,12 @@ extern crate log;
 
 mod buffer;
 mod date;
+mod error;
+mod limits;
 
 pub mod body;
 pub mod client;
 pub mod server;
+pub mod websocket;
+
+pub use crate::error::Error;
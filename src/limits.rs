@@ -0,0 +1,22 @@
+//! the limits of the http message head
+//!
+/// the limits applied when parsing the request or response head
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HeadLimits {
+    /// the maximum number of headers
+    pub max_headers: usize,
+    /// the maximum size of the head in bytes, including the start line
+    pub max_head_size: usize,
+    /// the maximum length of the request uri
+    pub max_uri_len: usize,
+}
+
+impl Default for HeadLimits {
+    fn default() -> Self {
+        HeadLimits {
+            max_headers: 64,
+            max_head_size: 64 << 10,
+            max_uri_len: 8 << 10,
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! the response compression middleware
+//!
+use std::sync::Arc;
+
+use http::header::*;
+use http::StatusCode;
+
+use crate::body::coding::{self, Coding};
+use crate::server::{Middleware, Next, Request, Response};
+
+/// middleware that compresses the response body
+///
+/// the coding is chosen by the `Accept-Encoding` of the request, from
+/// gzip and deflate with the `flate2` feature and br with the `brotli`
+/// feature. only the responses with an eligible content type and at
+/// least `min_size` bytes are compressed, the body with unknown size is
+/// always eligible. the compressed body is sent with the chunked
+/// encoding unless it's written by `Response::send`. the br coding
+/// needs a bigger stack, see `HttpServer::set_stack_size`
+///
+/// ```no_run
+/// use may_http::server::*;
+///
+/// fn hello(_req: Request, rsp: &mut Response) {
+///     rsp.headers_mut()
+///         .insert("content-type", "text/plain".parse().unwrap());
+///     rsp.send(&[b'a'; 4096]).unwrap();
+/// }
+///
+/// let service = ServiceBuilder::new()
+///     .layer(Compression::new().min_size(256))
+///     .service(hello);
+/// let server = HttpServer::new(service).start("127.0.0.1:8080").unwrap();
+/// server.wait();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: usize,
+    content_types: Arc<Vec<String>>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        let types = [
+            "text/*",
+            "application/json",
+            "application/javascript",
+            "application/xml",
+            "image/svg+xml",
+        ];
+        Compression {
+            min_size: 1024,
+            content_types: Arc::new(types.iter().map(|t| t.to_string()).collect()),
+        }
+    }
+}
+
+impl Compression {
+    /// create the middleware with the default settings
+    pub fn new() -> Self {
+        Compression::default()
+    }
+
+    /// set the minimum body size to compress, default is 1024
+    pub fn min_size(mut self, size: usize) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    /// set the eligible content types
+    ///
+    /// the type like `text/*` matches all the subtypes, default are the
+    /// text, json, javascript, xml and svg types
+    pub fn content_types<I, S>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let types = types.into_iter().map(|t| t.into().to_ascii_lowercase());
+        self.content_types = Arc::new(types.collect());
+        self
+    }
+
+    // check if the response could be compressed
+    pub(crate) fn is_eligible(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        if status == StatusCode::PARTIAL_CONTENT
+            || headers.contains_key(CONTENT_ENCODING)
+            || headers.contains_key(CONTENT_RANGE)
+        {
+            return false;
+        }
+        let no_transform = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .any(|v| v.to_str().is_ok_and(|v| v.contains("no-transform")));
+        if no_transform {
+            return false;
+        }
+        let mime = match headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+            Some(v) => v.split(';').next().unwrap_or_default().trim(),
+            None => return false,
+        };
+        self.content_types
+            .iter()
+            .any(|t| match t.strip_suffix('*') {
+                Some(prefix) => mime
+                    .as_bytes()
+                    .get(..prefix.len())
+                    .is_some_and(|m| m.eq_ignore_ascii_case(prefix.as_bytes())),
+                None => mime.eq_ignore_ascii_case(t),
+            })
+    }
+
+    // check if the body is large enough
+    pub(crate) fn is_large(&self, size: Option<usize>) -> bool {
+        size.is_none_or(|size| size >= self.min_size)
+    }
+}
+
+impl Middleware for Compression {
+    fn call(&self, req: Request, rsp: &mut Response, next: Next) {
+        let coding = coding::negotiate(req.headers());
+        rsp.set_compression(self.clone(), coding);
+        next.run(req, rsp)
+    }
+}
+
+// the compression chosen for the response
+#[derive(Debug)]
+pub(crate) struct Compress {
+    pub rule: Compression,
+    // `None` if the client doesn't accept any supported coding
+    pub coding: Option<Coding>,
+}
+
+#[cfg(all(test, feature = "flate2"))]
+mod tests {
+    use super::*;
+    use crate::server::{call, ServiceBuilder};
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::rc::Rc;
+
+    fn gunzip(data: &[u8]) -> String {
+        let mut s = String::new();
+        flate2::read::GzDecoder::new(data)
+            .read_to_string(&mut s)
+            .unwrap();
+        s
+    }
+
+    fn json(req: Request, rsp: &mut Response) {
+        rsp.headers_mut().insert(
+            CONTENT_TYPE,
+            "application/json; charset=utf-8".parse().unwrap(),
+        );
+        match req.uri().path() {
+            "/small" => rsp.send(b"[]").unwrap(),
+            "/stream" => {
+                rsp.set_content_length(4096);
+                rsp.write_all(&[b'1'; 4096]).unwrap();
+            }
+            _ => rsp.send(&[b'1'; 4096]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn compress_response() {
+        let service = ServiceBuilder::new()
+            .layer(Compression::new())
+            .service(json);
+        let expected = String::from_utf8(vec![b'1'; 4096]).unwrap();
+
+        // the whole body is compressed with a known length
+        let (head, body) = call(&service, "GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert!(head.contains("content-encoding: gzip\r\n"));
+        assert!(head.contains("vary: accept-encoding\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert_eq!(gunzip(&body), expected);
+
+        // the streamed body is switched to chunked
+        let raw = "GET /stream HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+        let (head, body) = call(&service, raw);
+        assert!(head.contains("content-encoding: gzip\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert!(head.contains("transfer-encoding: chunked\r\n"));
+        let body = Rc::new(RefCell::new(io::Cursor::new(body)));
+        let mut reader = crate::body::BodyReader::ChunkReader(body, None, Default::default());
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(gunzip(&data), expected);
+
+        // the HEAD response has the same headers without the body
+        for path in ["/", "/stream"] {
+            let raw = format!("HEAD {} HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n", path);
+            let (head, body) = call(&service, &raw);
+            assert!(head.contains("content-encoding: gzip\r\n"));
+            assert!(head.contains("vary: accept-encoding\r\n"));
+            assert!(body.is_empty());
+        }
+
+        // the small body and the client without the coding
+        let raw = "GET /small HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+        let (head, body) = call(&service, raw);
+        assert!(!head.contains("content-encoding"));
+        assert_eq!(body, b"[]");
+        let (head, body) = call(&service, "GET / HTTP/1.1\r\n\r\n");
+        assert!(!head.contains("content-encoding"));
+        assert!(head.contains("vary: accept-encoding\r\n"));
+        assert_eq!(body.len(), 4096);
+    }
+}
@@ -0,0 +1,532 @@
+//! the handle of a running server, used to stop it gracefully
+//!
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use may::coroutine::{self, Coroutine, JoinHandle};
+use may::go;
+use may::net::TcpStream;
+use may::sync::Semphore;
+
+// the connection is waiting for the next request
+const IDLE: usize = 0;
+// the connection is processing a request
+const BUSY: usize = 1;
+// the idle connection is closed by the shutdown
+const CLOSED: usize = 2;
+
+/// the connection state shared between the connection coroutine
+/// and the server handle
+pub(crate) struct Conn {
+    state: AtomicUsize,
+    // the milliseconds since the registry is created that the connection
+    // should be closed at, 0 means no deadline
+    deadline: AtomicU64,
+    // a clone of the socket, used to close the idle connection
+    stream: TcpStream,
+}
+
+impl Conn {
+    /// mark the connection idle before waiting for the next request
+    /// return false if the connection should be closed
+    pub fn set_idle(&self, conns: &Connections) -> bool {
+        self.state.store(IDLE, Ordering::SeqCst);
+        // the shutdown may miss the idle state, so check it again
+        !conns.is_closing()
+    }
+
+    /// mark the connection busy after receiving the request data
+    /// return false if the connection is already closed by the shutdown
+    pub fn set_busy(&self) -> bool {
+        self.state
+            .compare_exchange(IDLE, BUSY, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// close the connection if it's still waiting for the request
+    /// after the timeout, `None` clears the deadline
+    pub fn set_deadline(&self, conns: &Connections, timeout: Option<Duration>) {
+        let deadline = timeout.map_or(0, |t| conns.now() + t.as_millis() as u64 + 1);
+        self.deadline.store(deadline, Ordering::Relaxed);
+    }
+
+    /// close the write side and discard the unread input for a while
+    pub fn linger_close(&self) -> io::Result<()> {
+        linger_close(self.stream.try_clone()?)
+    }
+
+    // close the connection if it's idle
+    fn close_idle(&self) {
+        let idle = self
+            .state
+            .compare_exchange(IDLE, CLOSED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok();
+        if idle {
+            // wake up the blocked read
+            self.stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+}
+
+// how long the lingering close waits for the peer in total
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+// the maximum number of the rejected connections that linger at the same time
+const MAX_LINGERING: usize = 64;
+
+/// close the write side and discard the unread input for a while
+///
+/// closing the socket with unread input would reset the connection,
+/// and the peer may lose the response that not yet read
+fn linger_close(mut stream: TcpStream) -> io::Result<()> {
+    stream.shutdown(Shutdown::Write)?;
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let mut buf = [0u8; 4096];
+    let mut left = 64 * 1024;
+    while left > 0 {
+        // a trickling peer can't hold the connection beyond the deadline
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            break;
+        }
+        stream.set_read_timeout(Some(timeout))?;
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => left -= n.min(left),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// all the active connections of a server
+pub(crate) struct Connections {
+    closing: AtomicBool,
+    // the accept coroutine is still running
+    accepting: AtomicBool,
+    next_id: AtomicUsize,
+    conns: Mutex<HashMap<usize, (Arc<Conn>, Coroutine)>>,
+    // the base time of the connection deadlines
+    epoch: Instant,
+    // the free slots of the connection limit
+    permits: Option<Semphore>,
+    // the number of connections rejected by the limit
+    rejected: AtomicUsize,
+    // the number of the rejected connections in the lingering close
+    lingering: AtomicUsize,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections::new(None)
+    }
+}
+
+/// a slot of the connection limit, it's released when dropped
+pub(crate) struct Permit(Option<Arc<Connections>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let permits = self.0.as_ref().and_then(|c| c.permits.as_ref());
+        if let Some(permits) = permits {
+            permits.post();
+        }
+    }
+}
+
+// remove the connection from the registry when the coroutine exits
+struct ConnGuard(Arc<Connections>, usize);
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        if let Ok(mut conns) = self.0.conns.lock() {
+            conns.remove(&self.1);
+        }
+    }
+}
+
+/// mark the accept coroutine running until dropped
+pub(crate) struct Accepting(Arc<Connections>);
+
+impl Drop for Accepting {
+    fn drop(&mut self) {
+        self.0.accepting.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Connections {
+    /// create the registry with the connection limit
+    pub fn new(max: Option<usize>) -> Self {
+        Connections {
+            closing: AtomicBool::new(false),
+            accepting: AtomicBool::new(false),
+            next_id: AtomicUsize::new(0),
+            conns: Mutex::new(HashMap::new()),
+            epoch: Instant::now(),
+            permits: max.map(Semphore::new),
+            rejected: AtomicUsize::new(0),
+            lingering: AtomicUsize::new(0),
+        }
+    }
+
+    /// wait for a free slot of the connection limit
+    pub fn acquire(self: &Arc<Self>) -> Permit {
+        match self.permits {
+            Some(ref permits) => {
+                permits.wait();
+                Permit(Some(self.clone()))
+            }
+            None => Permit(None),
+        }
+    }
+
+    /// get a free slot without waiting, the failure is counted as rejected
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        match self.permits {
+            Some(ref permits) if !permits.try_wait() => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Some(_) => Some(Permit(Some(self.clone()))),
+            None => Some(Permit(None)),
+        }
+    }
+
+    /// close the rejected connection without blocking the accept loop
+    ///
+    /// the lingering close runs in the background, once too many of them
+    /// are running the connection is closed at once
+    pub fn close_rejected(self: &Arc<Self>, stream: TcpStream) {
+        if self.lingering.fetch_add(1, Ordering::Relaxed) >= MAX_LINGERING {
+            self.lingering.fetch_sub(1, Ordering::Relaxed);
+            stream.shutdown(Shutdown::Write).ok();
+            return;
+        }
+        let conns = self.clone();
+        go!(move || {
+            linger_close(stream).ok();
+            conns.lingering.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    /// mark the accept coroutine running, it's done when the guard is dropped
+    pub fn accepting(self: &Arc<Self>) -> Accepting {
+        self.accepting.store(true, Ordering::SeqCst);
+        Accepting(self.clone())
+    }
+
+    /// return true if the server is shutting down
+    #[inline]
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// spawn a coroutine by the builder to serve the connection and keep track of it
+    pub fn spawn<F>(
+        self: &Arc<Self>,
+        stream: TcpStream,
+        permit: Permit,
+        builder: coroutine::Builder,
+        f: F,
+    ) -> io::Result<()>
+    where
+        F: FnOnce(TcpStream, &Conn) + Send + 'static,
+    {
+        let conn = Arc::new(Conn {
+            state: AtomicUsize::new(BUSY),
+            deadline: AtomicU64::new(0),
+            stream: stream.try_clone()?,
+        });
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // hold the lock so that the guard can't remove the entry before it's inserted
+        let mut conns = self.conns.lock().unwrap();
+        // the guard is created in the coroutine, a failed spawn must not drop it
+        // while the lock is held
+        let registry = self.clone();
+        let c = conn.clone();
+        let h = go!(builder, move || {
+            let _guard = ConnGuard(registry, id);
+            let _permit = permit;
+            f(stream, &c)
+        })?;
+        conns.insert(id, (conn, h.coroutine().clone()));
+        Ok(())
+    }
+
+    // the milliseconds since the registry is created
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// spawn a coroutine that closes the connections whose deadline
+    /// is passed, it exits when the server is shutting down, or the
+    /// accept coroutine is done and no connection is left
+    pub fn watch(self: &Arc<Self>, tick: Duration) -> io::Result<()> {
+        // a stopped server must not be kept alive by the watcher
+        let conns = Arc::downgrade(self);
+        go!(
+            coroutine::Builder::new().name("ConnWatcher".to_owned()),
+            move || loop {
+                coroutine::sleep(tick);
+                let conns = match conns.upgrade() {
+                    Some(conns) => conns,
+                    None => break,
+                };
+                if conns.is_closing() || conns.is_done() {
+                    break;
+                }
+                conns.close_expired();
+            }
+        )?;
+        Ok(())
+    }
+
+    fn close_expired(&self) {
+        let now = self.now();
+        for (conn, _) in self.conns.lock().unwrap().values() {
+            let deadline = conn.deadline.load(Ordering::Relaxed);
+            if deadline != 0 && deadline <= now {
+                conn.stream.shutdown(Shutdown::Both).ok();
+            }
+        }
+    }
+
+    fn close_idle(&self) {
+        for (conn, _) in self.conns.lock().unwrap().values() {
+            conn.close_idle();
+        }
+    }
+
+    // the accept coroutine is gone and all the connections are closed
+    fn is_done(&self) -> bool {
+        !self.accepting.load(Ordering::SeqCst) && self.is_empty()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.conns.lock().unwrap().is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.conns.lock().unwrap().len()
+    }
+
+    // cancel all the remaining connections, return the number of dropped ones
+    fn cancel_all(&self) -> usize {
+        let conns = self.conns.lock().unwrap();
+        let mut dropped = 0;
+        for (conn, co) in conns.values() {
+            if conn.state.load(Ordering::SeqCst) != CLOSED {
+                dropped += 1;
+            }
+            conn.stream.shutdown(Shutdown::Both).ok();
+            unsafe { co.cancel() };
+        }
+        dropped
+    }
+}
+
+/// the handle of a running server that returned by `start`
+///
+/// dropping the handle would not stop the server, call `shutdown`
+/// to stop it gracefully
+pub struct ServerHandle {
+    co: JoinHandle<()>,
+    conns: Arc<Connections>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(co: JoinHandle<()>, conns: Arc<Connections>) -> Self {
+        ServerHandle { co, conns }
+    }
+
+    /// get the accept coroutine of the server
+    #[inline]
+    pub fn coroutine(&self) -> &Coroutine {
+        self.co.coroutine()
+    }
+
+    /// block until the accept coroutine exits
+    #[inline]
+    pub fn wait(&self) {
+        self.co.wait()
+    }
+
+    /// join the accept coroutine
+    #[inline]
+    pub fn join(self) -> thread::Result<()> {
+        self.co.join()
+    }
+
+    /// the number of the active connections
+    pub fn active_connections(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// the number of the connections that rejected by the connection limit
+    pub fn rejected_connections(&self) -> usize {
+        self.conns.rejected.load(Ordering::Relaxed)
+    }
+
+    /// shutdown the server gracefully
+    ///
+    /// the server stops accepting new connections and closes all the idle
+    /// keep-alive connections, the in-flight requests are allowed to finish
+    /// within the `grace` period, after that the remaining connections are
+    /// cancelled. return the number of connections that are dropped
+    pub fn shutdown(self, grace: Duration) -> usize {
+        let deadline = Instant::now() + grace;
+        self.conns.closing.store(true, Ordering::SeqCst);
+        // the listener is closed when the accept coroutine exits
+        unsafe { self.co.coroutine().cancel() };
+        self.co.join().ok();
+
+        self.conns.close_idle();
+        while !self.conns.is_empty() {
+            if Instant::now() >= deadline {
+                return self.conns.cancel_all();
+            }
+            coroutine::sleep(Duration::from_millis(10));
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::{HttpServer, Request, Response};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn sleep(req: Request, rsp: &mut Response) {
+        let ms = req.uri().path()[1..].parse().unwrap();
+        may::coroutine::sleep(Duration::from_millis(ms));
+        rsp.send(b"done").unwrap();
+    }
+
+    fn request(path: &str) -> TcpStream {
+        let mut s = TcpStream::connect("127.0.0.1:8094").unwrap();
+        write!(s, "GET {} HTTP/1.1\r\n\r\n", path).unwrap();
+        s
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let server = HttpServer::new(sleep).start("127.0.0.1:8094").unwrap();
+
+        // an idle keep-alive connection
+        let mut idle = request("/0");
+        let mut buf = [0u8; 1024];
+        let n = idle.read(&mut buf).unwrap();
+        assert!(buf[..n].ends_with(b"\r\n\r\ndone"));
+        // an in-flight request that finishes within the grace period
+        let mut slow = request("/200");
+        // an in-flight request that exceeds the grace period
+        let mut hang = request("/5000");
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(server.shutdown(Duration::from_millis(500)), 1);
+        assert!(TcpStream::connect("127.0.0.1:8094").is_err());
+
+        let mut rsp = String::new();
+        idle.read_to_string(&mut rsp).unwrap();
+        assert!(rsp.is_empty());
+        slow.read_to_string(&mut rsp).unwrap();
+        assert!(rsp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rsp.ends_with("\r\n\r\ndone"));
+        rsp.clear();
+        hang.read_to_string(&mut rsp).ok();
+        assert!(rsp.is_empty());
+    }
+
+    fn get(port: u16) -> TcpStream {
+        let mut s = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        s.write_all(b"GET /0 HTTP/1.1\r\n\r\n").unwrap();
+        s.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        s
+    }
+
+    #[test]
+    fn connection_limit() {
+        let mut server = HttpServer::new(sleep);
+        server.set_max_connections(Some(1));
+        let server = server.start("127.0.0.1:8103").unwrap();
+
+        let mut buf = [0u8; 1024];
+        let mut first = get(8103);
+        let n = first.read(&mut buf).unwrap();
+        assert!(buf[..n].ends_with(b"\r\n\r\ndone"));
+        assert_eq!(server.active_connections(), 1);
+        // the second connection waits in the backlog
+        let mut second = get(8103);
+        assert!(second.read(&mut buf).is_err());
+        drop(first);
+        let n = second.read(&mut buf).unwrap();
+        assert!(buf[..n].ends_with(b"\r\n\r\ndone"));
+        assert_eq!(server.rejected_connections(), 0);
+        server.shutdown(Duration::from_secs(1));
+
+        let mut server = HttpServer::new(sleep);
+        server
+            .set_max_connections(Some(1))
+            .set_reject_over_limit(true);
+        let server = server.start("127.0.0.1:8104").unwrap();
+        let mut first = get(8104);
+        let n = first.read(&mut buf).unwrap();
+        assert!(buf[..n].ends_with(b"\r\n\r\ndone"));
+        let mut second = get(8104);
+        let mut rsp = String::new();
+        second.read_to_string(&mut rsp).unwrap();
+        assert!(rsp.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(server.rejected_connections(), 1);
+        assert_eq!(server.active_connections(), 1);
+        server.shutdown(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn linger_deadline() {
+        let listener = may::net::TcpListener::bind("127.0.0.1:8112").unwrap();
+        let mut peer = TcpStream::connect("127.0.0.1:8112").unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        // the peer keeps trickling the data
+        let t = std::thread::spawn(move || {
+            for _ in 0..30 {
+                if peer.write_all(b"x").is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let now = Instant::now();
+        super::linger_close(stream).unwrap();
+        assert!(now.elapsed() < Duration::from_millis(1500));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn watcher_exit() {
+        let mut server = HttpServer::new(sleep);
+        server.set_keep_alive_timeout(Some(Duration::from_millis(100)));
+        let server = server.start("127.0.0.1:8115").unwrap();
+        let conns = server.conns.clone();
+        // only the watcher holds a weak reference
+        assert_eq!(Arc::weak_count(&conns), 1);
+
+        // stop the server without the shutdown
+        unsafe { server.coroutine().cancel() };
+        server.join().ok();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(Arc::weak_count(&conns), 0);
+    }
+}
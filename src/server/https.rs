@@ -0,0 +1,218 @@
+//! https server implementation on top of `MAY` and `rustls`
+//!
+use std::io::{self, Read, Write};
+use std::net::ToSocketAddrs;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use crate::server::handle::Conn;
+use crate::server::{HttpServer, HttpService, ServerHandle};
+use crate::Error;
+use may::net::{TcpListener, TcpStream};
+use may::{coroutine, go};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+/// this is the generic type https server
+/// with a type parameter that impl `HttpService` trait
+///
+/// the tls handshake is done inside the per connection coroutine,
+/// after that the connection is served the same way as `HttpServer`.
+/// all the `HttpServer` settings are available through deref, the
+/// header read timeout also limits the tls handshake
+///
+/// the tls handshake consumes more stack than plain http, so the
+/// connection coroutines are spawned with a stack size of 0x10000 by
+/// default, see `set_stack_size`
+pub struct HttpsServer<T: HttpService> {
+    server: HttpServer<T>,
+    tls_config: Arc<ServerConfig>,
+}
+
+impl<T: HttpService + Send + Sync + 'static> HttpsServer<T> {
+    /// create a https server with the certificate chain and private key
+    pub fn new(
+        server: T,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> io::Result<Self> {
+        let tls_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self::with_config(server, Arc::new(tls_config)))
+    }
+
+    /// create a https server with a customized tls configuration
+    pub fn with_config(server: T, tls_config: Arc<ServerConfig>) -> Self {
+        let mut server = HttpServer::new(server);
+        server.set_stack_size(Some(0x10000));
+        HttpsServer { server, tls_config }
+    }
+
+    /// Spawns the https service, binding to the given address
+    /// return a handle that you can use to shutdown the service
+    pub fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerHandle> {
+        let listener = TcpListener::bind(addr)?;
+        // the watcher exits once the accept coroutine is done
+        let accepting = self.conns.accepting();
+        self.watch_conns()?;
+        let conns = self.conns.clone();
+        let co = go!(
+            coroutine::Builder::new().name("TlsServer".to_owned()),
+            move || {
+                let _accepting = accepting;
+                let server = Arc::new(self);
+                loop {
+                    let (stream, permit) = match t_c!(server.accept(&listener, true)) {
+                        Some(conn) => conn,
+                        None => continue,
+                    };
+                    t_c!(server.config_stream(&stream));
+                    let builder = server.conn_builder();
+                    let s = server.clone();
+                    t_c!(server
+                        .conns
+                        .spawn(stream, permit, builder, move |stream, conn| {
+                            let stream = t!(s.accept_tls(stream, conn));
+                            s.serve_connection(stream, conn);
+                        }));
+                }
+            }
+        )?;
+        Ok(ServerHandle::new(co, conns))
+    }
+
+    // do the tls handshake on the accepted stream
+    fn accept_tls(&self, mut stream: TcpStream, conn: &Conn) -> io::Result<TlsStream> {
+        // a silent client can't hold the connection beyond the header timeout
+        conn.set_deadline(&self.conns, self.header_read_timeout);
+        let mut tls = ServerConnection::new(self.tls_config.clone())
+            .map_err(|e| io::Error::from(Error::Tls(e.into())))?;
+        while tls.is_handshaking() {
+            tls.complete_io(&mut stream)?;
+        }
+        Ok(TlsStream(StreamOwned::new(tls, stream)))
+    }
+}
+
+/// the server side tls stream
+/// it would send the `close_notify` alert when the connection is dropped
+struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl Read for TlsStream {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        let stream = &mut self.0;
+        stream.conn.send_close_notify();
+        while stream.conn.wants_write() {
+            if stream.conn.write_tls(&mut stream.sock).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl<T: HttpService> Deref for HttpsServer<T> {
+    type Target = HttpServer<T>;
+
+    /// deref to the underlying HttpServer
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.server
+    }
+}
+
+impl<T: HttpService> DerefMut for HttpsServer<T> {
+    /// deref_mut to the underlying HttpServer
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.server
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Request, Response};
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    fn hello(_req: Request, rsp: &mut Response) {
+        rsp.send(b"Hello Tls!").unwrap();
+    }
+
+    #[test]
+    fn https_hello() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert_der = cert.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+        let server = HttpsServer::new(hello, vec![cert_der.clone()], key.into()).unwrap();
+        let server = server.start("127.0.0.1:8443").unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert_der).unwrap();
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from("localhost").unwrap();
+        let conn = ClientConnection::new(Arc::new(config), name).unwrap();
+
+        let stream = std::net::TcpStream::connect("127.0.0.1:8443").unwrap();
+        let mut tls = StreamOwned::new(conn, stream);
+        tls.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut rsp = String::new();
+        tls.read_to_string(&mut rsp).unwrap();
+        assert!(rsp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rsp.ends_with("\r\n\r\nHello Tls!"));
+
+        unsafe { server.coroutine().cancel() };
+        server.join().ok();
+    }
+
+    #[test]
+    fn silent_client() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        let mut server =
+            HttpsServer::new(hello, vec![cert.cert.der().clone()], key.into()).unwrap();
+        server.set_header_read_timeout(Some(Duration::from_millis(100)));
+        let server = server.start("127.0.0.1:8113").unwrap();
+
+        // the client never starts the handshake
+        let mut stream = std::net::TcpStream::connect("127.0.0.1:8113").unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let now = std::time::Instant::now();
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(now.elapsed() < Duration::from_secs(1));
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(server.active_connections(), 0);
+
+        server.shutdown(Duration::from_secs(1));
+    }
+}
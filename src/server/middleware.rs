@@ -0,0 +1,209 @@
+//! middleware that wraps a http service with cross-cutting behavior
+//!
+use std::fmt;
+
+use crate::server::{HttpService, Request, Response};
+
+/// the middleware trait
+///
+/// a middleware can inspect or modify the request before passing it
+/// to the rest of the chain by `next.run()`, and modify the response
+/// after that. it can also answer the request directly without calling
+/// `next` at all
+pub trait Middleware {
+    /// process the request, call `next.run()` to invoke the inner service
+    fn call(&self, request: Request, response: &mut Response, next: Next);
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, &mut Response, Next),
+    F: Sync + Send,
+{
+    fn call(&self, req: Request, rsp: &mut Response, next: Next) {
+        self(req, rsp, next)
+    }
+}
+
+type BoxMiddleware = Box<dyn Middleware + Send + Sync>;
+
+/// the rest of the middleware chain and the inner service
+pub struct Next<'a> {
+    middlewares: &'a [BoxMiddleware],
+    service: &'a dyn HttpService,
+}
+
+impl Next<'_> {
+    /// pass the request to the next middleware or the inner service
+    pub fn run(self, req: Request, rsp: &mut Response) {
+        match self.middlewares.split_first() {
+            Some((m, rest)) => {
+                let next = Next {
+                    middlewares: rest,
+                    service: self.service,
+                };
+                m.call(req, rsp, next)
+            }
+            None => self.service.handle(req, rsp),
+        }
+    }
+}
+
+impl fmt::Debug for Next<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<Next middlewares={}>", self.middlewares.len())
+    }
+}
+
+/// builder to stack the middlewares around a service
+///
+/// the first added middleware is the outermost one, it sees the request
+/// first and the response last
+///
+/// ```no_run
+/// use may_http::server::*;
+///
+/// fn log(req: Request, rsp: &mut Response, next: Next) {
+///     let path = req.uri().path().to_owned();
+///     next.run(req, rsp);
+///     println!("{} {}", path, rsp.status());
+/// }
+///
+/// fn hello(_req: Request, rsp: &mut Response) {
+///     rsp.send(b"Hello World!").unwrap();
+/// }
+///
+/// let service = ServiceBuilder::new().layer(log).service(hello);
+/// let server = HttpServer::new(service).start("127.0.0.1:8080").unwrap();
+/// server.wait();
+/// ```
+#[derive(Default)]
+pub struct ServiceBuilder {
+    middlewares: Vec<BoxMiddleware>,
+}
+
+impl fmt::Debug for ServiceBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<ServiceBuilder middlewares={}>", self.middlewares.len())
+    }
+}
+
+impl ServiceBuilder {
+    /// create an empty builder
+    pub fn new() -> Self {
+        ServiceBuilder::default()
+    }
+
+    /// add a middleware inside the previous ones
+    pub fn layer<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware + Send + Sync + 'static,
+    {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// wrap the service with the middlewares
+    pub fn service<S: HttpService>(self, service: S) -> Layered<S> {
+        Layered {
+            middlewares: self.middlewares,
+            service,
+        }
+    }
+}
+
+/// the service that wrapped by the middlewares
+pub struct Layered<S> {
+    middlewares: Vec<BoxMiddleware>,
+    service: S,
+}
+
+impl<S: HttpService> HttpService for Layered<S> {
+    fn handle(&self, req: Request, rsp: &mut Response) {
+        let next = Next {
+            middlewares: &self.middlewares,
+            service: &self.service,
+        };
+        next.run(req, rsp)
+    }
+}
+
+/// middleware that overrides the maximum request body size
+///
+/// it can be used to set a different limit for a route of the `Router`,
+/// see `Request::set_max_body_size`
+#[derive(Debug, Clone, Copy)]
+pub struct MaxBodySize(pub Option<usize>);
+
+impl Middleware for MaxBodySize {
+    fn call(&self, mut req: Request, rsp: &mut Response, next: Next) {
+        req.set_max_body_size(self.0);
+        next.run(req, rsp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::call;
+    use http::header::*;
+    use http::StatusCode;
+
+    fn hello(_req: Request, rsp: &mut Response) {
+        rsp.headers_mut()
+            .append("x-trace", "hello".parse().unwrap());
+        rsp.send(b"Hello World!").unwrap();
+    }
+
+    fn auth(req: Request, rsp: &mut Response, next: Next) {
+        if req.headers().contains_key(AUTHORIZATION) {
+            return next.run(req, rsp);
+        }
+        *rsp.status_mut() = StatusCode::UNAUTHORIZED;
+        rsp.send(b"").unwrap();
+    }
+
+    // add a trace header before calling the inner service
+    struct Trace(&'static str);
+
+    impl Middleware for Trace {
+        fn call(&self, req: Request, rsp: &mut Response, next: Next) {
+            rsp.headers_mut().append("x-trace", self.0.parse().unwrap());
+            next.run(req, rsp);
+        }
+    }
+
+    #[test]
+    fn middleware_order() {
+        let service = ServiceBuilder::new()
+            .layer(Trace("outer"))
+            .layer(auth)
+            .layer(Trace("inner"))
+            .service(hello);
+
+        let (head, body) = call(&service, "GET / HTTP/1.1\r\nAuthorization: foo\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("x-trace: outer\r\nx-trace: inner\r\nx-trace: hello\r\n"));
+        assert_eq!(body, b"Hello World!");
+
+        // the auth middleware answers the request directly
+        let (head, _) = call(&service, "GET / HTTP/1.1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(head.contains("x-trace: outer\r\n"));
+        assert!(!head.contains("x-trace: inner\r\n"));
+    }
+
+    #[test]
+    fn closure_service() {
+        let service = ServiceBuilder::new()
+            .layer(|req: Request, rsp: &mut Response, next: Next| {
+                next.run(req, rsp);
+                rsp.headers_mut().insert(SERVER, "layered".parse().unwrap());
+            })
+            .service(|_req: Request, rsp: &mut Response| rsp.send(b"closure").unwrap());
+        let (head, body) = call(&service, "GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(body, b"closure");
+        // the head is already written when the inner service returns
+        assert!(!head.contains("layered"));
+    }
+}
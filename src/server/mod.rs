@@ -1,18 +1,63 @@
+macro_rules! t {
+    ($e: expr) => {
+        match $e {
+            Ok(val) => val,
+            Err(ref err)
+                if err.kind() == io::ErrorKind::ConnectionReset
+                    || err.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                // info!("http server read req: connection closed");
+                return;
+            }
+            Err(err) => {
+                error!("call = {:?}\nerr = {:?}", stringify!($e), err);
+                return;
+            }
+        }
+    };
+}
+
+macro_rules! t_c {
+    ($e: expr) => {
+        match $e {
+            Ok(val) => val,
+            Err(err) => {
+                error!("call = {:?}\nerr = {:?}", stringify!($e), err);
+                continue;
+            }
+        }
+    };
+}
+
+mod compress;
+mod handle;
+#[cfg(feature = "rustls")]
+mod https;
+mod middleware;
 mod request;
 mod response;
+mod router;
 mod server_impl;
 
 use std::cell::RefCell;
 use std::io::{self, Read, Write};
 use std::rc::Rc;
 
+use crate::buffer::BufferIo;
 use http::header::*;
-use http::Version;
+use http::{Method, StatusCode, Version};
 
+pub use self::compress::Compression;
+pub use self::handle::ServerHandle;
+pub use self::middleware::{Layered, MaxBodySize, Middleware, Next, ServiceBuilder};
 pub use self::request::Request;
 pub use self::response::Response;
+pub use self::router::{Params, Router};
 pub use self::server_impl::HttpServer;
 
+#[cfg(feature = "rustls")]
+pub use self::https::HttpsServer;
+
 /// the http service trait
 /// user code should supply a type that impl the `handle` method for the http server
 ///
@@ -64,21 +109,56 @@ fn process_request<S: Read + Write + 'static, T: HttpService>(
     server: &T,
     name: &str,
     mut req: Request,
-    stream: Rc<RefCell<S>>,
+    stream: Rc<RefCell<BufferIo<S>>>,
+    closing: bool,
+    max_body_size: Option<usize>,
+    decompress: bool,
 ) -> bool {
     req.set_reader(stream.clone());
+    if decompress {
+        // the body size limit applies to the decoded body
+        req.set_decoder();
+    }
+    req.set_max_body_size(max_body_size);
+    let too_large = req.too_large_flag();
+    if crate::websocket::is_upgrade_request(req.version(), req.headers()) {
+        req.set_upgrade(stream.clone());
+    }
     let version = req.version();
-    let mut rsp = Response::new(stream);
-    let mut keep_alive = should_keep_alive(version, req.headers());
+    let mut rsp = Response::new(stream.clone());
+    if req.method() == Method::HEAD {
+        rsp.set_head_request();
+    }
+    // don't keep the connection alive when the server is shutting down
+    let mut keep_alive = !closing && should_keep_alive(version, req.headers());
     if !keep_alive {
         rsp.headers_mut()
             .append(CONNECTION, "close".parse().unwrap());
     }
     rsp.headers_mut().append(SERVER, name.parse().unwrap());
     server.handle(req, &mut rsp);
+    if rsp.status() == StatusCode::SWITCHING_PROTOCOLS {
+        // the connection is taken over by the upgrade protocol
+        return false;
+    }
     if keep_alive {
         keep_alive = should_keep_alive(version, rsp.headers());
     }
+    if too_large.get() {
+        // the remaining body is not consumed, so close the connection
+        keep_alive = false;
+        if !rsp.is_head_written() {
+            *rsp.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+            rsp.headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+            rsp.send(b"").ok();
+        }
+    }
+    // the server loop would flush the data when there is no more
+    // pipelined request, so that all the responses are batched
+    stream.borrow_mut().set_defer_flush(true);
+    drop(rsp);
+    stream.borrow_mut().set_defer_flush(false);
     keep_alive
 }
 
@@ -105,3 +185,23 @@ pub fn should_keep_alive(version: Version, headers: &HeaderMap) -> bool {
         _ => true,
     }
 }
+
+// pass the raw request to the service, return the response head and body
+#[cfg(test)]
+pub(crate) fn call<S: HttpService>(service: &S, raw: &str) -> (String, Vec<u8>) {
+    let mut buf = bytes::BytesMut::from(raw.as_bytes());
+    let req = request::decode(&mut buf, &Default::default())
+        .unwrap()
+        .unwrap();
+    let out = Rc::new(RefCell::new(Vec::new()));
+    let mut rsp = Response::new(out.clone());
+    if req.method() == Method::HEAD {
+        rsp.set_head_request();
+    }
+    service.handle(req, &mut rsp);
+    drop(rsp);
+    let out = out.borrow();
+    let pos = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8(out[..pos].to_vec()).unwrap();
+    (head, out[pos..].to_vec())
+}
@@ -1,4 +1,4 @@
-use std::cell::RefCell;
+use std::cell::{Cell, Ref, RefCell};
 use std::fmt;
 use std::io::{self, Read};
 use std::ops::{Deref, DerefMut};
@@ -6,35 +6,66 @@ use std::rc::Rc;
 
 use bytes::{Bytes, BytesMut};
 use http::header::*;
-use http::{self, Method, Version};
+use http::{self, Version};
 use httparse;
 
-use crate::body::BodyReader;
+use crate::body::coding::content_codings;
+use crate::body::{content_length, is_chunked, BodyReader, ChunkInfo};
+use crate::limits::HeadLimits;
+use crate::server::Params;
+use crate::websocket::ReadWrite;
+use crate::Error;
 
-pub(crate) fn decode(buf: &mut BytesMut) -> io::Result<Option<Request>> {
+pub(crate) fn decode(buf: &mut BytesMut, limits: &HeadLimits) -> io::Result<Option<Request>> {
     #[inline]
     fn get_slice(buf: &Bytes, data: &[u8]) -> Bytes {
         let begin = data.as_ptr() as usize - buf.as_ptr() as usize;
         buf.slice(begin..begin + data.len())
     }
 
-    let mut headers: [httparse::Header; 64] =
-        unsafe { std::mem::MaybeUninit::uninit().assume_init() };
-    let mut r = httparse::Request::new(&mut headers);
-    let status = r.parse(buf).map_err(|e| {
-        let msg = format!("failed to parse http request: {:?}", e);
-        io::Error::new(io::ErrorKind::Other, msg)
+    // avoid the allocation for the common case
+    let mut stack_headers = [httparse::EMPTY_HEADER; 64];
+    let mut heap_headers = Vec::new();
+    let headers = if limits.max_headers <= stack_headers.len() {
+        &mut stack_headers[..limits.max_headers]
+    } else {
+        heap_headers.resize(limits.max_headers, httparse::EMPTY_HEADER);
+        &mut heap_headers[..]
+    };
+    // the parsed slices point into the buffer memory that would be split
+    // and frozen below, so detach the lifetime from the `buf` borrow
+    let data = unsafe { std::slice::from_raw_parts(buf.as_ptr(), buf.len()) };
+    let mut r = httparse::Request::new(headers);
+    let status = r.parse(data).map_err(|e| match e {
+        httparse::Error::TooManyHeaders => Error::HeadTooLarge,
+        httparse::Error::Version => Error::UnsupportedVersion,
+        e => Error::Parse(format!("failed to parse http request: {:?}", e)),
     })?;
 
-    let bytes = match status {
-        httparse::Status::Complete(amt) => {
-            #[allow(clippy::cast_ref_to_mut)]
-            let buf = unsafe { &mut *(buf as *const _ as *mut BytesMut) };
-            buf.split_to(amt).freeze()
+    let amt = match status {
+        httparse::Status::Complete(amt) => amt,
+        httparse::Status::Partial => {
+            // the request line is not complete yet
+            if data.len() > limits.max_uri_len && !data.contains(&b'\n') {
+                return Err(Error::UriTooLong.into());
+            }
+            // don't buffer the endless head
+            if data.len() > limits.max_head_size {
+                return Err(Error::HeadTooLarge.into());
+            }
+            return Ok(None);
         }
-        httparse::Status::Partial => return Ok(None),
     };
 
+    let path = r.path.unwrap();
+    if path.len() > limits.max_uri_len {
+        return Err(Error::UriTooLong.into());
+    }
+    if amt > limits.max_head_size {
+        return Err(Error::HeadTooLarge.into());
+    }
+    let bytes = buf.split_to(amt).freeze();
+
     let version = match r.version {
         Some(v) => {
             if v == 0 {
@@ -53,7 +84,7 @@ pub(crate) fn decode(buf: &mut BytesMut) -> io::Result<Option<Request>> {
     let mut req_builder = http::Request::builder();
     req_builder = req_builder
         .method(r.method.unwrap())
-        .uri(r.path.unwrap()) // can be optimized with Bytes
+        .uri(path) // can be optimized with Bytes
         .version(version);
 
     for header in r.headers.iter() {
@@ -62,42 +93,127 @@ pub(crate) fn decode(buf: &mut BytesMut) -> io::Result<Option<Request>> {
         req_builder = req_builder.header(header.name, value);
     }
 
-    req_builder
-        .body(BodyReader::EmptyReader)
-        .map(|req| Some(Request(req)))
-        .map_err(|e| {
-            let msg = format!("failed to build http request: {:?}", e);
-            io::Error::new(io::ErrorKind::Other, msg)
-        })
+    let req = req_builder.body(BodyReader::EmptyReader).map_err(|e| {
+        let msg = format!("failed to build http request: {:?}", e);
+        Error::Parse(msg)
+    })?;
+    check_framing(req.headers())?;
+    Ok(Some(Request {
+        inner: req,
+        upgrade: None,
+        too_large: Rc::new(Cell::new(false)),
+        chunk: Rc::new(RefCell::new(ChunkInfo::default())),
+    }))
+}
+
+// validate the body framing headers, the ambiguous framing could be
+// interpreted differently by a proxy and lead to request smuggling
+fn check_framing(headers: &HeaderMap) -> io::Result<()> {
+    let invalid = |msg: &str| io::Error::from(Error::Parse(msg.to_owned()));
+    let len = content_length(headers)?;
+    if headers.contains_key(TRANSFER_ENCODING) {
+        if len.is_some() {
+            return Err(invalid("both transfer encoding and content length"));
+        }
+        if !is_chunked(headers) {
+            return Err(invalid("the final transfer coding is not chunked"));
+        }
+    }
+    Ok(())
 }
 
 /// http server request
 /// a thin wraper to http::Request
 /// impl Read for reading http request body
-pub struct Request(http::Request<BodyReader>);
+pub struct Request {
+    inner: http::Request<BodyReader>,
+    // the connection that could be taken over by the upgrade protocol
+    upgrade: Option<Rc<RefCell<dyn ReadWrite>>>,
+    // set when the body exceeds the size limit
+    too_large: Rc<Cell<bool>>,
+    // the extensions and the trailers of the chunked body
+    chunk: Rc<RefCell<ChunkInfo>>,
+}
 
 impl Request {
     // set the body reader
     // this function would be called by the server to
     // set a proper `BodyReader` according to the request
     pub(crate) fn set_reader(&mut self, reader: Rc<RefCell<dyn Read>>) {
-        use std::str;
+        // the framing headers are already validated by `decode`
+        let size = content_length(self.headers()).unwrap_or_default();
+        let body_reader = match size {
+            Some(n) => BodyReader::SizedReader(reader, n),
+            None if is_chunked(self.headers()) => {
+                BodyReader::ChunkReader(reader, None, self.chunk.clone())
+            }
+            // the request without the framing headers has no body
+            None => return,
+        };
+
+        *self.body_mut() = body_reader;
+    }
 
-        if self.method() == Method::GET || self.method() == Method::HEAD {
+    // decode the compressed body, the codings are already checked by the server
+    // the headers are changed to describe the decoded body
+    pub(crate) fn set_decoder(&mut self) {
+        if let BodyReader::EmptyReader = *self.body() {
             return;
         }
+        let codings = match content_codings(self.headers()) {
+            Some(codings) if !codings.is_empty() => codings,
+            _ => return,
+        };
+        self.body_mut().decode(&codings);
+        let headers = self.headers_mut();
+        headers.remove(CONTENT_ENCODING);
+        headers.remove(CONTENT_LENGTH);
+    }
 
-        let size = self.headers().get(CONTENT_LENGTH).map(|v| {
-            let s = unsafe { str::from_utf8_unchecked(v.as_bytes()) };
-            s.parse().expect("failed to parse content length")
-        });
+    /// set the maximum body size for this request, `None` means unlimited
+    ///
+    /// this overrides the server setting, and should be called before
+    /// reading the body. once the limit is exceeded, reading the body
+    /// returns an `Error::BodyTooLarge` error and the server would answer
+    /// `413 Payload Too Large` and close the connection
+    pub fn set_max_body_size(&mut self, size: Option<usize>) {
+        let too_large = self.too_large.clone();
+        self.body_mut().limit(size, too_large);
+    }
 
-        let body_reader = match size {
-            Some(n) => BodyReader::SizedReader(reader, n),
-            None => BodyReader::ChunkReader(reader, None),
-        };
+    // the flag that set when the body exceeds the size limit
+    pub(crate) fn too_large_flag(&self) -> Rc<Cell<bool>> {
+        self.too_large.clone()
+    }
 
-        *self.body_mut() = body_reader;
+    /// get the trailer fields of the chunked body
+    ///
+    /// it's empty until the body is read to the end
+    pub fn trailers(&self) -> Ref<'_, HeaderMap> {
+        Ref::map(self.chunk.borrow(), ChunkInfo::trailers)
+    }
+
+    /// get the `name[=value]` extensions of the last read chunk
+    ///
+    /// each read returns the data of at most one chunk, so they can be
+    /// checked after each read of the chunked body
+    pub fn chunk_extensions(&self) -> Ref<'_, [(String, Option<String>)]> {
+        Ref::map(self.chunk.borrow(), ChunkInfo::extensions)
+    }
+
+    /// get the path parameter captured by the `Router`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.extensions().get::<Params>()?.get(name)
+    }
+
+    // set the connection for the upgrade request
+    pub(crate) fn set_upgrade(&mut self, stream: Rc<RefCell<dyn ReadWrite>>) {
+        self.upgrade = Some(stream);
+    }
+
+    // take the connection for the upgrade protocol
+    pub(crate) fn take_upgrade(&mut self) -> Option<Rc<RefCell<dyn ReadWrite>>> {
+        self.upgrade.take()
     }
 }
 
@@ -107,7 +223,7 @@ impl Deref for Request {
     /// deref to the http::Request
     #[inline]
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.inner
     }
 }
 
@@ -115,7 +231,7 @@ impl DerefMut for Request {
     /// deref_mut to the http::Request
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.inner
     }
 }
 
@@ -2,13 +2,15 @@
 //!
 //! These are responses sent by a `may_http::Server` to clients, after
 //! receiving a request.
-use std::cell::RefCell;
+use std::cell::{RefCell, RefMut};
 use std::fmt;
 use std::io::{self, Write};
 use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
 
-use crate::body::BodyWriter;
+use crate::body::coding::Coding;
+use crate::body::{trailer_names, BodyWriter};
+use crate::server::compress::{Compress, Compression};
 use http::header::*;
 use http::{self, StatusCode};
 
@@ -27,6 +29,12 @@ pub struct Response {
     writer: Rc<RefCell<dyn Write>>,
     // the cached response size
     body_size: Option<usize>,
+    // the response of a HEAD request, the body is discarded
+    head_request: bool,
+    // the compression set by the `Compression` middleware
+    compress: Option<Compress>,
+    // the trailer fields that written after the chunked body
+    trailers: Rc<RefCell<HeaderMap>>,
 }
 
 impl fmt::Debug for Response {
@@ -43,9 +51,29 @@ impl Response {
             raw_rsp: http::Response::new(BodyWriter::InvalidWriter),
             writer: stream,
             body_size: None,
+            head_request: false,
+            compress: None,
+            trailers: Rc::new(RefCell::new(HeaderMap::new())),
         }
     }
 
+    // set the content coding headers if the body should be compressed
+    // the compression is only decided once
+    fn start_coding(&mut self, size: Option<usize>) -> Option<Coding> {
+        let Compress { rule, coding } = self.compress.take()?;
+        if !rule.is_eligible(self.status(), self.headers()) || !rule.is_large(size) {
+            return None;
+        }
+        let headers = self.headers_mut();
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+        let coding = coding?;
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(coding.name()));
+        headers.remove(CONTENT_LENGTH);
+        // the compressed size is unknown
+        self.body_size = None;
+        Some(coding)
+    }
+
     // actual write head to stream
     fn write_head_impl(&mut self) -> io::Result<()> {
         let mut writer = self.writer.borrow_mut();
@@ -87,12 +115,29 @@ impl Response {
             }
             c if c.is_informational() => BodyWriter::EmptyWriter(self.writer.clone()),
             _ => {
-                if let Some(size) = self.body_size {
-                    BodyWriter::SizedWriter(self.writer.clone(), size)
-                } else {
+                let coding = self.start_coding(self.body_size);
+                let names = trailer_names(&self.trailers.borrow());
+                if let Some(names) = names {
+                    // only the chunked body can carry the trailers
+                    self.headers_mut().insert(TRAILER, names);
+                    self.body_size = None;
+                }
+                // the compressed body is always chunked
+                if self.body_size.is_none() {
                     self.headers_mut()
                         .append(TRANSFER_ENCODING, "chunked".parse().unwrap());
-                    BodyWriter::ChunkWriter(self.writer.clone())
+                }
+                let trailers = self.trailers.clone();
+                if self.head_request {
+                    // keep the same headers as the GET response, but no body
+                    BodyWriter::EmptyWriter(self.writer.clone())
+                } else if let Some(coding) = coding {
+                    let chunked = BodyWriter::ChunkWriter(self.writer.clone(), trailers);
+                    BodyWriter::EncodeWriter(coding.encoder(chunked))
+                } else if let Some(size) = self.body_size {
+                    BodyWriter::SizedWriter(self.writer.clone(), size)
+                } else {
+                    BodyWriter::ChunkWriter(self.writer.clone(), trailers)
                 }
             }
         };
@@ -131,6 +176,14 @@ impl Response {
     /// ```
     #[inline]
     pub fn send(&mut self, body: &[u8]) -> io::Result<()> {
+        if self.compress.is_some() && !self.is_head_written() {
+            // the whole body is compressed to get the length
+            if let Some(coding) = self.start_coding(Some(body.len())) {
+                let body = coding.encode(body)?;
+                self.body_size = Some(body.len());
+                return self.write_all(&body);
+            }
+        }
         self.body_size = Some(body.len());
         self.write_all(body)
     }
@@ -142,6 +195,43 @@ impl Response {
     pub fn set_content_length(&mut self, len: usize) {
         self.body_size = Some(len);
     }
+
+    /// get the trailer fields that sent after the body
+    ///
+    /// the trailers set before writing the body are announced by the
+    /// `Trailer` header and the body is sent with the chunked encoding.
+    /// they can also be set after that until the response is finished,
+    /// but they are dropped if the body is not chunked
+    pub fn trailers_mut(&mut self) -> RefMut<'_, HeaderMap> {
+        self.trailers.borrow_mut()
+    }
+
+    /// set the trailer fields that sent after the body, see `trailers_mut`
+    pub fn set_trailers(&mut self, trailers: HeaderMap) {
+        *self.trailers.borrow_mut() = trailers;
+    }
+
+    // compress the body with the coding, see the `Compression` middleware
+    pub(crate) fn set_compression(&mut self, rule: Compression, coding: Option<Coding>) {
+        self.compress = Some(Compress { rule, coding });
+    }
+
+    // the response is for a HEAD request, the body would not be sent
+    pub(crate) fn set_head_request(&mut self) {
+        self.head_request = true;
+    }
+
+    // check if the response head is already written
+    pub(crate) fn is_head_written(&self) -> bool {
+        !matches!(*self.body(), BodyWriter::InvalidWriter)
+    }
+
+    // write out the protocol switching response head
+    // after that the connection is taken over by the new protocol
+    pub(crate) fn switch_protocol(&mut self) -> io::Result<()> {
+        *self.body_mut() = self.write_head()?;
+        self.body_mut().flush()
+    }
 }
 
 impl Deref for Response {
@@ -168,6 +258,10 @@ impl Write for Response {
         if let BodyWriter::InvalidWriter = *self.body() {
             *self.body_mut() = self.write_head()?;
         }
+        if self.head_request {
+            // pretend the body is written
+            return Ok(msg.len());
+        }
         self.body_mut().write(msg)
     }
 
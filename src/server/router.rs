@@ -0,0 +1,417 @@
+//! http service that dispatch the requests by method and path
+//!
+use std::fmt;
+
+use http::header::*;
+use http::{Method, StatusCode};
+
+use crate::server::{HttpService, Request, Response};
+
+/// the captured path parameters of the matched route
+///
+/// it's stored in the request extensions, use `Request::param` to get them.
+/// the values are percent-decoded, the one that is not valid utf8 after
+/// decoding is kept as it is in the path
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    /// get the parameter value by name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// iterate over the name and value pairs
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// return true if there is no parameter
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// a segment of the route pattern
+#[derive(Debug)]
+enum Segment {
+    // matches the exact segment
+    Static(String),
+    // `:name`, matches any non-empty segment
+    Param(String),
+    // `*name`, matches all the remaining segments
+    Wildcard(String),
+}
+
+// the parsed route pattern
+#[derive(Debug)]
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    fn parse(path: &str) -> Self {
+        let mut segments = Vec::new();
+        for seg in split(path) {
+            if let Some(name) = seg.strip_prefix(':') {
+                segments.push(Segment::Param(name.to_owned()));
+            } else if let Some(name) = seg.strip_prefix('*') {
+                segments.push(Segment::Wildcard(name.to_owned()));
+                // nothing can follow the wildcard
+                break;
+            } else {
+                segments.push(Segment::Static(seg.to_owned()));
+            }
+        }
+        Pattern(segments)
+    }
+
+    // match the path segments, the captured parameters are pushed to `params`
+    // return the number of consumed segments, a prefix match only needs to
+    // match the leading segments
+    fn matches(&self, path: &[&str], prefix: bool, params: &mut Params) -> Option<usize> {
+        let mut i = 0;
+        for seg in &self.0 {
+            match seg {
+                Segment::Static(s) => {
+                    if path.get(i) != Some(&s.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => match path.get(i) {
+                    Some(v) if !v.is_empty() => params.0.push((name.clone(), decode(v))),
+                    _ => return None,
+                },
+                Segment::Wildcard(name) => {
+                    let rest = path.get(i..).unwrap_or_default().join("/");
+                    params.0.push((name.clone(), decode(&rest)));
+                    return Some(path.len());
+                }
+            }
+            i += 1;
+        }
+        if prefix || i == path.len() {
+            Some(i)
+        } else {
+            None
+        }
+    }
+}
+
+// split the path into segments, the leading slash is ignored
+fn split(path: &str) -> Vec<&str> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    if path.is_empty() {
+        Vec::new()
+    } else {
+        path.split('/').collect()
+    }
+}
+
+// percent-decode the path, the invalid utf8 result keeps the raw path
+fn decode(path: &str) -> String {
+    if !path.contains('%') {
+        return path.to_owned();
+    }
+    let hex = |b: &u8| (*b as char).to_digit(16);
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hi = bytes.get(i + 1).and_then(hex);
+            let lo = bytes.get(i + 2).and_then(hex);
+            if let (Some(hi), Some(lo)) = (hi, lo) {
+                out.push((hi * 16 + lo) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(out).unwrap_or_else(|_| path.to_owned())
+}
+
+type BoxService = Box<dyn HttpService + Send + Sync>;
+
+enum Entry {
+    Route(Method, Pattern, BoxService),
+    Mount(Pattern, Router),
+}
+
+// the dispatch result
+enum Matched<'a> {
+    Route(&'a BoxService, Params),
+    // the path matches but the method doesn't
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+/// a http service that dispatch the requests to the routes
+///
+/// the route pattern is a path with optional `:name` segments that
+/// capture one path segment, and an optional trailing `*name` segment
+/// that captures the rest of the path. the routes are matched in the
+/// order they are added. the HEAD request without a HEAD route is
+/// handled by the GET route. the unmatched requests get a `404 Not Found`
+/// response, and a `405 Method Not Allowed` response with the `Allow`
+/// header if only the method doesn't match
+///
+/// ```no_run
+/// use may_http::server::*;
+///
+/// fn user(req: Request, rsp: &mut Response) {
+///     let id = req.param("id").unwrap();
+///     rsp.send(id.as_bytes()).unwrap();
+/// }
+///
+/// let mut api = Router::new();
+/// api.get("/users/:id", user);
+/// let mut router = Router::new();
+/// router.mount("/api", api);
+/// let server = HttpServer::new(router).start("127.0.0.1:8080").unwrap();
+/// server.wait();
+/// ```
+#[derive(Default)]
+pub struct Router {
+    entries: Vec<Entry>,
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<Router routes={}>", self.entries.len())
+    }
+}
+
+impl Router {
+    /// create an empty router
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// add a route for the method and path pattern
+    pub fn route<S>(&mut self, method: Method, path: &str, service: S) -> &mut Self
+    where
+        S: HttpService + Send + Sync + 'static,
+    {
+        let entry = Entry::Route(method, Pattern::parse(path), Box::new(service));
+        self.entries.push(entry);
+        self
+    }
+
+    /// add a GET route
+    pub fn get<S>(&mut self, path: &str, service: S) -> &mut Self
+    where
+        S: HttpService + Send + Sync + 'static,
+    {
+        self.route(Method::GET, path, service)
+    }
+
+    /// add a POST route
+    pub fn post<S>(&mut self, path: &str, service: S) -> &mut Self
+    where
+        S: HttpService + Send + Sync + 'static,
+    {
+        self.route(Method::POST, path, service)
+    }
+
+    /// add a PUT route
+    pub fn put<S>(&mut self, path: &str, service: S) -> &mut Self
+    where
+        S: HttpService + Send + Sync + 'static,
+    {
+        self.route(Method::PUT, path, service)
+    }
+
+    /// add a DELETE route
+    pub fn delete<S>(&mut self, path: &str, service: S) -> &mut Self
+    where
+        S: HttpService + Send + Sync + 'static,
+    {
+        self.route(Method::DELETE, path, service)
+    }
+
+    /// mount the sub router under the prefix
+    ///
+    /// the prefix is stripped from the path before matching the sub router
+    /// routes, the prefix can also capture parameters
+    pub fn mount(&mut self, prefix: &str, router: Router) -> &mut Self {
+        self.entries
+            .push(Entry::Mount(Pattern::parse(prefix), router));
+        self
+    }
+
+    fn find(&self, method: &Method, path: &[&str], params: &Params) -> Matched<'_> {
+        let mut allow = Vec::new();
+        for entry in &self.entries {
+            let mut p = params.clone();
+            match entry {
+                Entry::Route(m, pattern, service) => {
+                    if pattern.matches(path, false, &mut p).is_none() {
+                        continue;
+                    }
+                    if m == method {
+                        return Matched::Route(service, p);
+                    }
+                    if !allow.contains(m) {
+                        allow.push(m.clone());
+                    }
+                }
+                Entry::Mount(pattern, router) => {
+                    let n = match pattern.matches(path, true, &mut p) {
+                        Some(n) => n,
+                        None => continue,
+                    };
+                    match router.find(method, &path[n..], &p) {
+                        Matched::Route(service, p) => return Matched::Route(service, p),
+                        Matched::MethodNotAllowed(methods) => {
+                            for m in methods {
+                                if !allow.contains(&m) {
+                                    allow.push(m);
+                                }
+                            }
+                        }
+                        Matched::NotFound => {}
+                    }
+                }
+            }
+        }
+        if allow.is_empty() {
+            Matched::NotFound
+        } else {
+            Matched::MethodNotAllowed(allow)
+        }
+    }
+}
+
+impl HttpService for Router {
+    fn handle(&self, mut req: Request, rsp: &mut Response) {
+        let matched = {
+            let path = split(req.uri().path());
+            match self.find(req.method(), &path, &Params::default()) {
+                // fall back to the GET route
+                Matched::MethodNotAllowed(_) if req.method() == Method::HEAD => {
+                    self.find(&Method::GET, &path, &Params::default())
+                }
+                matched => matched,
+            }
+        };
+        match matched {
+            Matched::Route(service, params) => {
+                req.extensions_mut().insert(params);
+                service.handle(req, rsp)
+            }
+            Matched::MethodNotAllowed(mut allow) => {
+                // the GET route also serves HEAD
+                if let Some(i) = allow.iter().position(|m| m == Method::GET) {
+                    if !allow.contains(&Method::HEAD) {
+                        allow.insert(i + 1, Method::HEAD);
+                    }
+                }
+                let allow = allow
+                    .iter()
+                    .map(|m| m.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                *rsp.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+                rsp.headers_mut().insert(ALLOW, allow.parse().unwrap());
+                rsp.send(b"").ok();
+            }
+            Matched::NotFound => {
+                *rsp.status_mut() = StatusCode::NOT_FOUND;
+                rsp.send(b"").ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo_params(req: Request, rsp: &mut Response) {
+        let params = req.extensions().get::<Params>().unwrap();
+        let s = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        rsp.send(s.as_bytes()).unwrap();
+    }
+
+    fn dispatch(router: &Router, method: &str, path: &str) -> (String, String) {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+        let (head, body) = crate::server::call(router, &raw);
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn route_params() {
+        let mut router = Router::new();
+        router
+            .get("/", echo_params)
+            .get("/users/:id", echo_params)
+            .post("/users/:id", echo_params)
+            .get("/users/:id/posts/:post", echo_params)
+            .get("/static/*path", echo_params);
+
+        assert_eq!(dispatch(&router, "GET", "/").1, "");
+        assert_eq!(dispatch(&router, "GET", "/users/42").1, "id=42");
+        assert_eq!(dispatch(&router, "POST", "/users/42?x=1").1, "id=42");
+        assert_eq!(
+            dispatch(&router, "GET", "/users/42/posts/7").1,
+            "id=42&post=7"
+        );
+        let (_, body) = dispatch(&router, "GET", "/static/css/main.css");
+        assert_eq!(body, "path=css/main.css");
+        assert_eq!(dispatch(&router, "GET", "/static").1, "path=");
+        // the values are percent-decoded
+        assert_eq!(dispatch(&router, "GET", "/users/a%20b").1, "id=a b");
+        let (_, body) = dispatch(&router, "GET", "/static/a%2Fb/%E4%BD%A0");
+        assert_eq!(body, "path=a/b/你");
+        assert_eq!(dispatch(&router, "GET", "/users/%ZZ%ff").1, "id=%ZZ%ff");
+
+        // the HEAD request is handled by the GET route
+        let (head, body) = dispatch(&router, "HEAD", "/users/42");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Length: 5\r\n"));
+        assert_eq!(body, "");
+
+        let (head, _) = dispatch(&router, "GET", "/users/");
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let (head, _) = dispatch(&router, "GET", "/users/42/posts");
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let (head, _) = dispatch(&router, "DELETE", "/users/42");
+        assert!(head.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(head.contains("\r\nallow: GET, HEAD, POST\r\n"));
+    }
+
+    #[test]
+    fn mount_router() {
+        let mut users = Router::new();
+        users.get("/", echo_params).delete("/:id", echo_params);
+        let mut api = Router::new();
+        api.mount("/users", users);
+        let mut router = Router::new();
+        router
+            .mount("/api/:version", api)
+            .get("/api/:version/ping", echo_params);
+
+        assert_eq!(dispatch(&router, "GET", "/api/v1/users").1, "version=v1");
+        let (_, body) = dispatch(&router, "DELETE", "/api/v1/users/3");
+        assert_eq!(body, "version=v1&id=3");
+        // falls through the mounted router
+        assert_eq!(dispatch(&router, "GET", "/api/v2/ping").1, "version=v2");
+
+        let (head, _) = dispatch(&router, "GET", "/api/v1/users/3");
+        assert!(head.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(head.contains("\r\nallow: DELETE\r\n"));
+        let (head, _) = dispatch(&router, "HEAD", "/api/v1/users/3");
+        assert!(head.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        let (head, _) = dispatch(&router, "GET", "/api/v1/posts");
+        assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -1,47 +1,25 @@
 //! http server implementation on top of `MAY`
 //!
 use std::cell::RefCell;
-use std::io;
+use std::io::{self, Read, Write};
 use std::net::ToSocketAddrs;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
 
+use super::handle::{Conn, Connections, Permit, ServerHandle};
+use crate::body::coding::content_codings;
 use crate::buffer::BufferIo;
-use crate::server::HttpService;
-use may::net::TcpListener;
+use crate::limits::HeadLimits;
+use crate::server::{HttpService, Response};
+use crate::Error;
+use http::header::*;
+use http::StatusCode;
+use may::net::{TcpListener, TcpStream};
 use may::{coroutine, go};
 
-macro_rules! t {
-    ($e: expr) => {
-        match $e {
-            Ok(val) => val,
-            Err(ref err)
-                if err.kind() == io::ErrorKind::ConnectionReset
-                    || err.kind() == io::ErrorKind::UnexpectedEof =>
-            {
-                // info!("http server read req: connection closed");
-                return;
-            }
-            Err(err) => {
-                error!("call = {:?}\nerr = {:?}", stringify!($e), err);
-                return;
-            }
-        }
-    };
-}
-
-macro_rules! t_c {
-    ($e: expr) => {
-        match $e {
-            Ok(val) => val,
-            Err(err) => {
-                error!("call = {:?}\nerr = {:?}", stringify!($e), err);
-                continue;
-            }
-        }
-    };
-}
+// the handler that writes the error response
+type ErrorHandler = Box<dyn Fn(&mut Response) + Send + Sync>;
 
 /// this is the generic type http server
 /// with a type parameter that impl `HttpService` trait
@@ -51,6 +29,17 @@ pub struct HttpServer<T: HttpService> {
     name: String,
     read_timeout: Option<Duration>,
     write_timeout: Option<Duration>,
+    stack_size: Option<usize>,
+    keep_alive_timeout: Option<Duration>,
+    pub(super) header_read_timeout: Option<Duration>,
+    max_requests: Option<usize>,
+    reject_over_limit: bool,
+    max_body_size: Option<usize>,
+    decompress: bool,
+    limits: HeadLimits,
+    error_handler: Option<ErrorHandler>,
+    // the active connections, used by the graceful shutdown
+    pub(super) conns: Arc<Connections>,
 }
 
 impl<T: HttpService + Send + Sync + 'static> HttpServer<T> {
@@ -61,6 +50,16 @@ impl<T: HttpService + Send + Sync + 'static> HttpServer<T> {
             name: String::from("Example"),
             read_timeout: None,
             write_timeout: None,
+            stack_size: None,
+            keep_alive_timeout: None,
+            header_read_timeout: None,
+            max_requests: None,
+            reject_over_limit: false,
+            max_body_size: None,
+            decompress: false,
+            limits: HeadLimits::default(),
+            error_handler: None,
+            conns: Arc::new(Connections::default()),
         }
     }
 
@@ -76,6 +75,129 @@ impl<T: HttpService + Send + Sync + 'static> HttpServer<T> {
         self
     }
 
+    /// set the stack size of the connection coroutines, default is `None`
+    /// that uses the `may` default
+    ///
+    /// the size is in words like `may::config().set_stack_size()`. the tls
+    /// handshake and the brotli coding need a bigger stack than plain http
+    pub fn set_stack_size(&mut self, size: Option<usize>) -> &mut Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// set how long an idle keep-alive connection waits for the next
+    /// request, default is `None` that waits forever
+    ///
+    /// unlike the read timeout, it doesn't apply to reading the request
+    /// body, and the check is cheap
+    pub fn set_keep_alive_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// set how long the server waits for a complete request head,
+    /// default is `None` that waits forever
+    ///
+    /// the timer starts when the first byte of the request is received,
+    /// or when the connection is accepted for the first request. the
+    /// connection is closed after the timeout
+    pub fn set_header_read_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.header_read_timeout = timeout;
+        self
+    }
+
+    /// set the maximum number of requests served on a connection,
+    /// default is unlimited
+    ///
+    /// the response of the last request has the `Connection: close` header
+    pub fn set_max_requests_per_connection(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_requests = max;
+        self
+    }
+
+    /// set the maximum number of the active connections, default is unlimited
+    ///
+    /// once the limit is reached, the server stops accepting until a
+    /// connection is closed, the new connections are queued in the listen
+    /// backlog. see `set_reject_over_limit` to reject them instead
+    pub fn set_max_connections(&mut self, max: Option<usize>) -> &mut Self {
+        self.conns = Arc::new(Connections::new(max));
+        self
+    }
+
+    /// reject the connections over the limit instead of pausing the accept
+    ///
+    /// the rejected connection is answered with `503 Service Unavailable`
+    /// and closed immediately, the https connection is closed without
+    /// a response
+    pub fn set_reject_over_limit(&mut self, reject: bool) -> &mut Self {
+        self.reject_over_limit = reject;
+        self
+    }
+
+    /// set the maximum request body size, default is unlimited
+    ///
+    /// the request exceeds the limit is answered with `413 Payload Too Large`
+    /// and the connection is closed, it can be overridden for each request by
+    /// `Request::set_max_body_size`
+    pub fn set_max_body_size(&mut self, size: Option<usize>) -> &mut Self {
+        self.max_body_size = size;
+        self
+    }
+
+    /// decode the compressed request body, default is false
+    ///
+    /// the body is decoded by the `Content-Encoding` of the request, the
+    /// gzip and deflate codings need the `flate2` feature, and the br
+    /// coding needs the `brotli` feature. the request with other codings is
+    /// answered with `415 Unsupported Media Type` and the connection is
+    /// closed. the maximum body size applies to the decoded body
+    pub fn set_decompress_requests(&mut self, decompress: bool) -> &mut Self {
+        self.decompress = decompress;
+        self
+    }
+
+    /// set the maximum number of request headers, default is 64
+    ///
+    /// the request with more headers is answered with
+    /// `431 Request Header Fields Too Large`
+    pub fn set_max_headers(&mut self, max: usize) -> &mut Self {
+        self.limits.max_headers = max;
+        self
+    }
+
+    /// set the maximum size of the request head, default is 64KiB
+    ///
+    /// the limit is checked while receiving the head, the larger request
+    /// is answered with `431 Request Header Fields Too Large`
+    pub fn set_max_head_size(&mut self, size: usize) -> &mut Self {
+        self.limits.max_head_size = size;
+        self
+    }
+
+    /// set the maximum length of the request uri, default is 8KiB
+    ///
+    /// the request with a longer uri is answered with `414 URI Too Long`
+    pub fn set_max_uri_len(&mut self, len: usize) -> &mut Self {
+        self.limits.max_uri_len = len;
+        self
+    }
+
+    /// set the handler that writes the error response for the malformed requests
+    ///
+    /// the response status is already set to one of `400 Bad Request`,
+    /// `414 URI Too Long`, `415 Unsupported Media Type`, `431 Request Header
+    /// Fields Too Large` or `505 HTTP Version Not Supported`, and the
+    /// connection is closed after the response. by default the body is the
+    /// status reason
+    pub fn set_error_handler<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&mut Response) + Send + Sync + 'static,
+    {
+        self.error_handler = Some(Box::new(f));
+        self
+    }
+
     /// set the serer name
     pub fn set_server_name(&mut self, name: String) -> &mut Self {
         self.name = name;
@@ -83,56 +205,566 @@ impl<T: HttpService + Send + Sync + 'static> HttpServer<T> {
     }
 
     /// Spawns the http service, binding to the given address
-    /// return a coroutine that you can cancel it when need to stop the service
-    pub fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<coroutine::JoinHandle<()>> {
+    /// return a handle that you can use to shutdown the service
+    pub fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<ServerHandle> {
         let listener = TcpListener::bind(addr)?;
-        go!(
+        // the watcher exits once the accept coroutine is done
+        let accepting = self.conns.accepting();
+        self.watch_conns()?;
+        let conns = self.conns.clone();
+        let co = go!(
             coroutine::Builder::new().name("TcpServer".to_owned()),
             move || {
+                let _accepting = accepting;
                 let server = Arc::new(self);
-                for stream in listener.incoming() {
-                    let stream = t_c!(stream);
-                    t_c!(stream.set_read_timeout(server.read_timeout));
-                    t_c!(stream.set_write_timeout(server.write_timeout));
-                    let server = server.clone();
-                    go!(move || {
-                        let mut stream = BufferIo::new(stream);
-                        loop {
-                            match t!(super::request::decode(stream.get_reader_buf())) {
-                                None => {
-                                    // need more data
-                                    if t!(stream.bump_read()) == 0 {
-                                        // break the connection
-                                        return;
-                                    };
-                                }
-                                Some(req) => {
-                                    if !t!(super::handle_expect(&req, &mut stream)) {
-                                        // close the connection
-                                        return;
-                                    };
-                                    let io = Rc::new(RefCell::new(stream));
-                                    if !super::process_request(
-                                        &server.inner,
-                                        &server.name,
-                                        req,
-                                        io.clone(),
-                                    ) {
-                                        // close the connection
-                                        return;
-                                    }
-                                    // since handle is done, the reader should be released
-                                    stream = Rc::try_unwrap(io).expect("no reader").into_inner();
-                                }
-                            }
+                loop {
+                    let (stream, permit) = match t_c!(server.accept(&listener, false)) {
+                        Some(conn) => conn,
+                        None => continue,
+                    };
+                    t_c!(server.config_stream(&stream));
+                    let builder = server.conn_builder();
+                    let s = server.clone();
+                    t_c!(server
+                        .conns
+                        .spawn(stream, permit, builder, move |stream, conn| {
+                            s.serve_connection(stream, conn)
+                        }));
+                }
+            }
+        )?;
+        Ok(ServerHandle::new(co, conns))
+    }
+
+    // the builder of the connection coroutines
+    pub(super) fn conn_builder(&self) -> coroutine::Builder {
+        let builder = coroutine::Builder::new();
+        match self.stack_size {
+            Some(size) => builder.stack_size(size),
+            None => builder,
+        }
+    }
+
+    // close the connections that exceed the idle or header timeout
+    pub(super) fn watch_conns(&self) -> io::Result<()> {
+        let timeouts = [self.keep_alive_timeout, self.header_read_timeout];
+        let min = match timeouts.iter().flatten().min() {
+            Some(&min) => min,
+            None => return Ok(()),
+        };
+        // check a few times within the timeout
+        let tick = (min / 4).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        self.conns.watch(tick)
+    }
+
+    // accept the next connection within the connection limit
+    // return `None` if the connection is rejected
+    pub(super) fn accept(
+        &self,
+        listener: &TcpListener,
+        tls: bool,
+    ) -> io::Result<Option<(TcpStream, Permit)>> {
+        if !self.reject_over_limit {
+            // leave the new connections in the backlog
+            let permit = self.conns.acquire();
+            let (stream, _) = listener.accept()?;
+            return Ok(Some((stream, permit)));
+        }
+        let (mut stream, _) = listener.accept()?;
+        match self.conns.try_acquire() {
+            Some(permit) => Ok(Some((stream, permit))),
+            None => {
+                if !tls {
+                    // the send buffer of the new socket is empty, it won't block
+                    let rsp = b"HTTP/1.1 503 Service Unavailable\r\n\
+                                Content-Length: 0\r\nConnection: close\r\n\r\n";
+                    stream.write_all(rsp).ok();
+                    self.conns.close_rejected(stream);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // apply the socket settings to the accepted stream
+    pub(super) fn config_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)
+    }
+
+    // answer the malformed request with the status and close the connection
+    fn write_error<S: Write + 'static>(
+        &self,
+        stream: BufferIo<S>,
+        status: StatusCode,
+        conn: &Conn,
+    ) -> io::Result<()> {
+        let stream = Rc::new(RefCell::new(stream));
+        {
+            let mut rsp = Response::new(stream.clone());
+            *rsp.status_mut() = status;
+            rsp.headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+            rsp.headers_mut().insert(SERVER, self.name.parse().unwrap());
+            match self.error_handler {
+                Some(ref f) => f(&mut rsp),
+                None => {
+                    let reason = status.canonical_reason().unwrap_or("");
+                    rsp.send(reason.as_bytes())?;
+                }
+            }
+        }
+        stream.borrow_mut().flush()?;
+        conn.linger_close()
+    }
+
+    // process the requests on the connection until it's closed
+    pub(super) fn serve_connection<S: Read + Write + 'static>(&self, stream: S, conn: &Conn) {
+        let mut stream = BufferIo::new(stream);
+        // the number of served requests
+        let mut served = 0;
+        // the header read timer is started
+        let mut reading_head = true;
+        conn.set_deadline(&self.conns, self.header_read_timeout);
+        loop {
+            let req = match super::request::decode(stream.get_reader_buf(), &self.limits) {
+                Ok(req) => req,
+                Err(e) => {
+                    let e = Error::from(e);
+                    let status = match e {
+                        Error::Parse(_) => StatusCode::BAD_REQUEST,
+                        Error::HeadTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                        Error::UriTooLong => StatusCode::URI_TOO_LONG,
+                        Error::UnsupportedVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+                        _ => {
+                            error!("failed to decode request: {:?}", e);
+                            return;
                         }
-                    });
+                    };
+                    info!("bad request: {}", e);
+                    t!(self.write_error(stream, status, conn));
+                    return;
+                }
+            };
+            match req {
+                None => {
+                    // all the pipelined requests are processed
+                    // write out the batched responses
+                    t!(stream.flush());
+                    // no partial request, wait for the next one
+                    let idle = stream.get_reader_buf().is_empty();
+                    if idle && !conn.set_idle(&self.conns) {
+                        return;
+                    }
+                    if idle && served > 0 {
+                        // wait for the next request on the keep-alive connection
+                        conn.set_deadline(&self.conns, self.keep_alive_timeout);
+                        reading_head = false;
+                    } else if !reading_head {
+                        conn.set_deadline(&self.conns, self.header_read_timeout);
+                        reading_head = true;
+                    }
+                    // need more data
+                    if t!(stream.bump_read()) == 0 {
+                        // break the connection
+                        return;
+                    };
+                    if idle && !conn.set_busy() {
+                        // closed by the shutdown
+                        return;
+                    }
+                }
+                Some(req) => {
+                    if self.decompress && content_codings(req.headers()).is_none() {
+                        info!(
+                            "unsupported content coding: {:?}",
+                            req.headers()[CONTENT_ENCODING]
+                        );
+                        let status = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                        t!(self.write_error(stream, status, conn));
+                        return;
+                    }
+                    conn.set_deadline(&self.conns, None);
+                    reading_head = false;
+                    served += 1;
+                    if !t!(super::handle_expect(&req, &mut stream)) {
+                        // close the connection
+                        return;
+                    };
+                    let io = Rc::new(RefCell::new(stream));
+                    // close the connection after the last request
+                    let last = self.max_requests.is_some_and(|max| served >= max);
+                    let closing = last || self.conns.is_closing();
+                    let keep_alive = super::process_request(
+                        &self.inner,
+                        &self.name,
+                        req,
+                        io.clone(),
+                        closing,
+                        self.max_body_size,
+                        self.decompress,
+                    );
+                    // since handle is done, the reader should be released
+                    stream = match Rc::try_unwrap(io) {
+                        Ok(io) => io.into_inner(),
+                        Err(_) => panic!("no reader"),
+                    };
+                    if !keep_alive || closing || self.conns.is_closing() {
+                        // close the connection
+                        t!(stream.flush());
+                        return;
+                    }
                 }
             }
-        )
+        }
     }
 }
 
-// TODO: pub struct HttpsServer<T>(pub T);
-// TODO: support web socket
-// TODO: support pipeline server
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{MaxBodySize, Request, Response, Router, ServiceBuilder};
+    use crate::Error;
+    use std::io::{Read, Write};
+
+    fn echo(mut req: Request, rsp: &mut Response) {
+        let mut body = req.uri().path().as_bytes().to_vec();
+        req.read_to_end(&mut body).unwrap();
+        rsp.send(&body).unwrap();
+    }
+
+    #[test]
+    fn pipeline_requests() {
+        let server = HttpServer::new(echo).start("127.0.0.1:8093").unwrap();
+        let mut s = std::net::TcpStream::connect("127.0.0.1:8093").unwrap();
+        s.write_all(
+            b"GET /1 HTTP/1.1\r\n\r\n\
+              POST /2 HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+              POST /3 HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nworld\r\n0\r\n\r\n\
+              GET /4 HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+        let mut rsp = String::new();
+        s.read_to_string(&mut rsp).unwrap();
+        assert_eq!(rsp.matches("HTTP/1.1 200 OK\r\n").count(), 4);
+        let bodies = [
+            "\r\n\r\n/1",
+            "\r\n\r\n/2hello",
+            "\r\n\r\n/3world",
+            "\r\n\r\n/4",
+        ];
+        let mut pos = 0;
+        for body in bodies.iter() {
+            pos += rsp[pos..].find(body).unwrap() + body.len();
+        }
+        assert_eq!(pos, rsp.len());
+
+        unsafe { server.coroutine().cancel() };
+        server.join().ok();
+    }
+
+    fn upload(mut req: Request, rsp: &mut Response) {
+        let mut body = Vec::new();
+        match req.read_to_end(&mut body) {
+            Ok(_) => rsp.send(&body).unwrap(),
+            Err(e) => assert!(matches!(Error::from(e), Error::BodyTooLarge)),
+        }
+    }
+
+    fn request(port: u16, raw: &[u8]) -> String {
+        let mut s = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        s.write_all(raw).unwrap();
+        let mut rsp = String::new();
+        s.read_to_string(&mut rsp).unwrap();
+        rsp
+    }
+
+    #[test]
+    fn body_size_limit() {
+        let mut router = Router::new();
+        router.post("/", upload).post(
+            "/big",
+            ServiceBuilder::new()
+                .layer(MaxBodySize(Some(16)))
+                .service(upload),
+        );
+        let mut server = HttpServer::new(router);
+        server.set_max_body_size(Some(8));
+        let server = server.start("127.0.0.1:8096").unwrap();
+
+        // the connection is closed after the 413 response
+        let rsp = request(
+            8096,
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+              POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhelloworld\
+              POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        assert!(rsp.starts_with("HTTP/1.1 200 OK\r\n"));
+        let pos = rsp.find("\r\n\r\nhello").unwrap() + 9;
+        let rsp = &rsp[pos..];
+        assert!(rsp.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(rsp.contains("\r\nconnection: close\r\n"));
+        assert!(rsp.ends_with("\r\n\r\n"));
+
+        // the chunked body is counted while reading
+        let rsp = request(
+            8096,
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n",
+        );
+        assert!(rsp.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+        // the limit is overridden by the route
+        let rsp = request(
+            8096,
+            b"POST /big HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+              5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n",
+        );
+        assert!(rsp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rsp.ends_with("\r\n\r\nhelloworld"));
+
+        server.shutdown(Duration::from_secs(1));
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn request_decompress() {
+        let mut server = HttpServer::new(upload);
+        server
+            .set_max_body_size(Some(64))
+            .set_decompress_requests(true)
+            .set_stack_size(Some(0x10000))
+            .set_error_handler(|rsp| {
+                let body = format!("error {}", rsp.status().as_u16());
+                rsp.send(body.as_bytes()).unwrap();
+            });
+        let server = server.start("127.0.0.1:8109").unwrap();
+        let gzip = |data: &[u8]| {
+            let body = crate::body::coding::Coding::Gzip.encode(data).unwrap();
+            let head = format!(
+                "POST / HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n",
+                body.len()
+            );
+            [head.into_bytes(), body].concat()
+        };
+
+        let rsp = request(8109, &gzip(b"hello"));
+        assert!(rsp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rsp.ends_with("\r\n\r\nhello"));
+
+        // the limit applies to the decoded size
+        let rsp = request(8109, &gzip(&[0; 1024]));
+        assert!(rsp.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+        let rsp = request(
+            8109,
+            b"POST / HTTP/1.1\r\nContent-Encoding: compress\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        assert!(rsp.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+        // the error handler writes the 415 response
+        assert!(rsp.ends_with("\r\n\r\nerror 415"));
+
+        server.shutdown(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn malformed_request() {
+        let mut server = HttpServer::new(echo);
+        server.set_error_handler(|rsp| {
+            let body = format!("error {}", rsp.status().as_u16());
+            rsp.send(body.as_bytes()).unwrap();
+        });
+        let server = server.start("127.0.0.1:8097").unwrap();
+
+        let rsp = request(8097, b"GET / HTTP/1.1\r\nbad header\r\n\r\n");
+        assert!(rsp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(rsp.contains("\r\nconnection: close\r\n"));
+        assert!(rsp.ends_with("\r\n\r\nerror 400"));
+
+        let rsp = request(8097, b"GET / HTTP/2.0\r\n\r\n");
+        assert!(rsp.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..65 {
+            raw.extend_from_slice(format!("x-{}: {}\r\n", i, i).as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+        let rsp = request(8097, &raw);
+        assert!(rsp.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        let raw = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(9000));
+        let rsp = request(8097, raw.as_bytes());
+        assert!(rsp.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+
+        // the request before the malformed one is still answered
+        let rsp = request(8097, b"GET /ok HTTP/1.1\r\n\r\nGET\r\n\r\n");
+        assert!(rsp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rsp.ends_with("\r\n\r\nerror 400"));
+
+        server.shutdown(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn invalid_framing() {
+        let server = HttpServer::new(echo).start("127.0.0.1:8099").unwrap();
+
+        let bad: &[&[u8]] = &[
+            b"POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: +1\r\n\r\na",
+            b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            b"POST / HTTP/1.1\r\nContent-Length: 1, 2\r\n\r\nab",
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+        ];
+        for raw in bad {
+            let rsp = request(8099, raw);
+            assert!(rsp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", rsp);
+        }
+
+        // the identical duplicated lengths are allowed
+        let rsp = request(
+            8099,
+            b"POST / HTTP/1.1\r\nContent-Length: 2, 2\r\nConnection: close\r\n\r\nab",
+        );
+        assert!(rsp.ends_with("\r\n\r\n/ab"));
+        // the body of a GET request is not treated as the next request
+        let rsp = request(
+            8099,
+            b"GET / HTTP/1.1\r\nContent-Length: 19\r\nConnection: close\r\n\r\n\
+              GET /x HTTP/1.1\r\n\r\n",
+        );
+        assert!(rsp.ends_with("\r\n\r\n/GET /x HTTP/1.1\r\n\r\n"));
+        // the request without the framing headers has no body
+        let rsp = request(
+            8099,
+            b"POST /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(rsp.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(rsp.contains("\r\n\r\n/aHTTP/1.1 200 OK\r\n"));
+        assert!(rsp.ends_with("\r\n\r\n/b"));
+
+        server.shutdown(Duration::from_secs(1));
+
+        // the client rejects the invalid response length without panic
+        let listener = std::net::TcpListener::bind("127.0.0.1:8100").unwrap();
+        let t = std::thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = s.read(&mut buf).unwrap();
+            s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1x\r\n\r\n")
+                .unwrap();
+        });
+        let mut client = crate::client::HttpClient::connect("127.0.0.1:8100").unwrap();
+        assert!(client.get("/".parse().unwrap()).is_err());
+        t.join().unwrap();
+    }
+
+    fn stream(req: Request, rsp: &mut Response) {
+        if req.uri().path() != "/stream" {
+            return echo(req, rsp);
+        }
+        rsp.write_all(b"hello").unwrap();
+        // the empty write doesn't end the chunked body
+        assert_eq!(rsp.write(b"").unwrap(), 0);
+        rsp.write_all(b"world").unwrap();
+    }
+
+    #[test]
+    fn head_request() {
+        let server = HttpServer::new(stream).start("127.0.0.1:8111").unwrap();
+
+        // the HEAD response has the headers of the GET response
+        let rsp = request(
+            8111,
+            b"HEAD /a HTTP/1.1\r\n\r\nHEAD /stream HTTP/1.1\r\n\r\n\
+              GET /stream HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        let rsps: Vec<_> = rsp.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(rsps.len(), 3);
+        assert!(rsps[0].contains("\r\nContent-Length: 2\r\n"));
+        assert!(rsps[0].ends_with("\r\n\r\n"));
+        assert!(rsps[1].contains("\r\ntransfer-encoding: chunked\r\n"));
+        assert!(rsps[1].ends_with("\r\n\r\n"));
+        assert!(rsps[2].ends_with("\r\n\r\n5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n"));
+
+        server.shutdown(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn connection_timeouts() {
+        let mut server = HttpServer::new(echo);
+        server
+            .set_keep_alive_timeout(Some(Duration::from_millis(100)))
+            .set_header_read_timeout(Some(Duration::from_millis(100)))
+            .set_max_requests_per_connection(Some(2));
+        let server = server.start("127.0.0.1:8102").unwrap();
+
+        // the idle keep-alive connection is closed
+        let now = std::time::Instant::now();
+        let rsp = request(8102, b"GET /a HTTP/1.1\r\n\r\n");
+        assert!(rsp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(rsp.ends_with("\r\n\r\n/a"));
+        assert!(now.elapsed() >= Duration::from_millis(100));
+
+        // the incomplete request head
+        let now = std::time::Instant::now();
+        let rsp = request(8102, b"GET /b HTTP/1.1\r\n");
+        assert!(rsp.is_empty());
+        assert!(now.elapsed() >= Duration::from_millis(100));
+
+        // the second response closes the connection
+        let rsp = request(
+            8102,
+            b"GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\n\r\nGET /3 HTTP/1.1\r\n\r\n",
+        );
+        assert_eq!(rsp.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        let (first, second) = rsp.split_at(rsp.rfind("HTTP/1.1 200 OK\r\n").unwrap());
+        assert!(first.ends_with("\r\n\r\n/1"));
+        assert!(second.contains("\r\nconnection: close\r\n"));
+        assert!(second.ends_with("\r\n\r\n/2"));
+
+        server.shutdown(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn head_limits() {
+        let mut server = HttpServer::new(echo);
+        server
+            .set_max_headers(100)
+            .set_max_head_size(4096)
+            .set_max_uri_len(16);
+        let server = server.start("127.0.0.1:8098").unwrap();
+
+        let mut raw = b"GET /ok HTTP/1.1\r\nConnection: close\r\n".to_vec();
+        for i in 0..80 {
+            raw.extend_from_slice(format!("x-{}: {}\r\n", i, i).as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+        let rsp = request(8098, &raw);
+        assert!(rsp.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // the endless head is rejected without waiting for the end
+        let mut raw = b"GET /ok HTTP/1.1\r\n".to_vec();
+        while raw.len() <= 4096 {
+            raw.extend_from_slice(b"x-foo: bar\r\n");
+        }
+        let rsp = request(8098, &raw);
+        assert!(rsp.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        let rsp = request(8098, b"GET /long/long/long/long HTTP/1.1\r\n\r\n");
+        assert!(rsp.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+
+        // the client limits the response head
+        let mut client = crate::client::HttpClient::connect("127.0.0.1:8098").unwrap();
+        client.set_max_headers(2);
+        assert!(client.get("/ok".parse().unwrap()).is_err());
+        let mut client = crate::client::HttpClient::connect("127.0.0.1:8098").unwrap();
+        client.set_max_head_size(32);
+        assert!(client.get("/ok".parse().unwrap()).is_err());
+
+        server.shutdown(Duration::from_secs(1));
+    }
+}
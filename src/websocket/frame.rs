@@ -0,0 +1,242 @@
+//! websocket frame codec, see RFC 6455 section 5.2
+//!
+use std::io::{self, Read, Write};
+
+use crate::Error;
+
+/// the frame opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OpCode {
+    Continue,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(code: u8) -> Option<OpCode> {
+        match code {
+            0x0 => Some(OpCode::Continue),
+            0x1 => Some(OpCode::Text),
+            0x2 => Some(OpCode::Binary),
+            0x8 => Some(OpCode::Close),
+            0x9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            OpCode::Continue => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    /// control frames are close, ping and pong
+    pub(crate) fn is_control(self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+/// the decoded frame head
+#[derive(Debug)]
+pub(crate) struct FrameHead {
+    pub fin: bool,
+    pub opcode: OpCode,
+    pub mask: Option<[u8; 4]>,
+    pub len: u64,
+}
+
+#[inline]
+fn invalid(msg: &'static str) -> io::Error {
+    Error::Parse(msg.to_owned()).into()
+}
+
+/// apply the mask on the data in place
+pub(crate) fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[i & 3];
+    }
+}
+
+/// read a frame head from the stream
+pub(crate) fn read_head(r: &mut dyn Read) -> io::Result<FrameHead> {
+    let mut head = [0u8; 2];
+    r.read_exact(&mut head)?;
+
+    let fin = head[0] & 0x80 != 0;
+    if head[0] & 0x70 != 0 {
+        return Err(invalid("websocket frame with reserved bits"));
+    }
+    let opcode = OpCode::from_u8(head[0] & 0x0F).ok_or_else(|| invalid("unknown opcode"))?;
+
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut buf = [0u8; 2];
+            r.read_exact(&mut buf)?;
+            u64::from(u16::from_be_bytes(buf))
+        }
+        127 => {
+            let mut buf = [0u8; 8];
+            r.read_exact(&mut buf)?;
+            let len = u64::from_be_bytes(buf);
+            if len >> 63 != 0 {
+                return Err(invalid("websocket frame length overflow"));
+            }
+            len
+        }
+        n => u64::from(n),
+    };
+
+    if opcode.is_control() && (len > 125 || !fin) {
+        return Err(invalid("invalid websocket control frame"));
+    }
+
+    let mask = if head[1] & 0x80 != 0 {
+        let mut mask = [0u8; 4];
+        r.read_exact(&mut mask)?;
+        Some(mask)
+    } else {
+        None
+    };
+
+    Ok(FrameHead {
+        fin,
+        opcode,
+        mask,
+        len,
+    })
+}
+
+/// read the frame payload from the stream and unmask it
+///
+/// the caller should check the length against the limit first
+pub(crate) fn read_payload(r: &mut dyn Read, head: &FrameHead) -> io::Result<Vec<u8>> {
+    let mut payload = vec![0u8; head.len as usize];
+    r.read_exact(&mut payload)?;
+    if let Some(mask) = head.mask {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(payload)
+}
+
+/// write a frame to the stream, the payload is masked if `mask` is set
+pub(crate) fn write_frame(
+    w: &mut dyn Write,
+    fin: bool,
+    opcode: OpCode,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mut head = [0u8; 14];
+    head[0] = opcode.as_u8() | if fin { 0x80 } else { 0 };
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+
+    let len = payload.len();
+    let mut n = if len < 126 {
+        head[1] = mask_bit | len as u8;
+        2
+    } else if len <= 0xFFFF {
+        head[1] = mask_bit | 126;
+        head[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        4
+    } else {
+        head[1] = mask_bit | 127;
+        head[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+        10
+    };
+
+    match mask {
+        Some(mask) => {
+            head[n..n + 4].copy_from_slice(&mask);
+            n += 4;
+            w.write_all(&head[..n])?;
+            let mut data = payload.to_vec();
+            apply_mask(&mut data, mask);
+            w.write_all(&data)
+        }
+        None => {
+            w.write_all(&head[..n])?;
+            w.write_all(payload)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_frame(raw: &[u8]) -> io::Result<(FrameHead, Vec<u8>)> {
+        let mut r = raw;
+        let head = read_head(&mut r)?;
+        let payload = read_payload(&mut r, &head)?;
+        assert!(r.is_empty());
+        Ok((head, payload))
+    }
+
+    #[test]
+    fn unmasked_text_frame() {
+        // the single-frame unmasked text message example in RFC 6455
+        let raw = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        let (head, payload) = read_frame(&raw).unwrap();
+        assert!(head.fin);
+        assert!(head.mask.is_none());
+        assert_eq!(head.opcode, OpCode::Text);
+        assert_eq!(payload, b"Hello");
+
+        let mut buf = Vec::new();
+        write_frame(&mut buf, true, OpCode::Text, b"Hello", None).unwrap();
+        assert_eq!(buf, raw);
+    }
+
+    #[test]
+    fn masked_text_frame() {
+        // the single-frame masked text message example in RFC 6455
+        let raw = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (head, payload) = read_frame(&raw).unwrap();
+        assert_eq!(head.mask, Some([0x37, 0xfa, 0x21, 0x3d]));
+        assert_eq!(payload, b"Hello");
+
+        let mut buf = Vec::new();
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        write_frame(&mut buf, true, OpCode::Text, b"Hello", Some(mask)).unwrap();
+        assert_eq!(buf, raw);
+    }
+
+    #[test]
+    fn frame_length() {
+        for &len in &[125, 126, 0xFFFF, 0x10000] {
+            let data = vec![7u8; len];
+            let mut buf = Vec::new();
+            write_frame(&mut buf, false, OpCode::Binary, &data, None).unwrap();
+            let (head, payload) = read_frame(&buf).unwrap();
+            assert!(!head.fin);
+            assert_eq!(head.opcode, OpCode::Binary);
+            assert_eq!(head.len, len as u64);
+            assert_eq!(payload, data);
+        }
+    }
+
+    #[test]
+    fn invalid_frame_head() {
+        // fragmented ping
+        let raw = [0x09, 0x00];
+        assert!(read_head(&mut &raw[..]).is_err());
+        // reserved bits
+        let raw = [0xC1, 0x00];
+        assert!(read_head(&mut &raw[..]).is_err());
+        // unknown opcode
+        let raw = [0x83, 0x00];
+        let e = read_head(&mut &raw[..]).unwrap_err();
+        assert!(matches!(Error::from(e), Error::Parse(_)));
+    }
+}
@@ -0,0 +1,180 @@
+//! websocket support on top of the http connection
+//!
+//! the server handler detects the upgrade request with `is_upgrade`
+//! and then take over the connection by `WebSocket::accept`, the
+//! client side websocket is created by `HttpClient::websocket`
+//!
+//! ```no_run
+//! use may_http::server::*;
+//! use may_http::websocket::{self, Message, WebSocket};
+//!
+//! fn echo(req: Request, rsp: &mut Response) {
+//!     if !websocket::is_upgrade(&req) {
+//!         return rsp.send(b"not a websocket request").unwrap();
+//!     }
+//!     let mut ws = match WebSocket::accept(req, rsp) {
+//!         Ok(ws) => ws,
+//!         Err(_) => return,
+//!     };
+//!     while let Ok(msg) = ws.read_message() {
+//!         match msg {
+//!             Message::Text(_) | Message::Binary(_) => ws.write_message(msg).unwrap(),
+//!             Message::Close(_) => break,
+//!             _ => {}
+//!         }
+//!     }
+//! }
+//! ```
+mod frame;
+mod socket;
+
+use std::io::{self, Read, Write};
+
+use http::header::*;
+use http::{StatusCode, Version};
+use sha1::{Digest, Sha1};
+
+use crate::Error;
+
+pub(crate) use self::socket::Role;
+pub use self::socket::WebSocket;
+
+/// the stream that the websocket is built on
+pub(crate) trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
+
+/// the websocket configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// the maximum size of a single frame, larger outgoing messages
+    /// are split into fragments of this size
+    pub max_frame_size: usize,
+    /// the maximum size of a message, including all the fragments
+    pub max_message_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_frame_size: 16 << 20,
+            max_message_size: 64 << 20,
+        }
+    }
+}
+
+/// the close frame payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    /// the status code, see RFC 6455 section 7.4
+    pub code: u16,
+    /// the close reason
+    pub reason: String,
+}
+
+/// the websocket message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// a utf-8 text message
+    Text(String),
+    /// a binary message
+    Binary(Vec<u8>),
+    /// a ping message, the pong reply is sent automatically
+    Ping(Vec<u8>),
+    /// a pong message
+    Pong(Vec<u8>),
+    /// the close message
+    Close(Option<CloseFrame>),
+}
+
+// the magic guid for the handshake
+const WS_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// calculate the `Sec-WebSocket-Accept` value for the key
+pub(crate) fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(WS_GUID);
+    base64::encode(sha1.finalize())
+}
+
+// fill the buffer from the os random source, rfc 6455 requires the
+// masking keys to be unpredictable
+fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    getrandom::getrandom(buf).map_err(io::Error::from)
+}
+
+/// generate a masking key for the client frame
+pub(crate) fn mask_key() -> io::Result<[u8; 4]> {
+    let mut key = [0u8; 4];
+    fill_random(&mut key)?;
+    Ok(key)
+}
+
+/// generate a `Sec-WebSocket-Key` value for the client handshake
+pub(crate) fn client_key() -> io::Result<String> {
+    let mut key = [0u8; 16];
+    fill_random(&mut key)?;
+    Ok(base64::encode(key))
+}
+
+/// check the server handshake response for the client key
+pub(crate) fn check_response(rsp: &crate::client::Response, key: &str) -> io::Result<()> {
+    let invalid = |msg: &str| Err(Error::Parse(msg.to_owned()).into());
+    if rsp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return invalid("websocket upgrade rejected by the server");
+    }
+    let headers = rsp.headers();
+    if !has_token(headers, CONNECTION, "upgrade") || !has_token(headers, UPGRADE, "websocket") {
+        return invalid("invalid websocket upgrade response");
+    }
+    let accept = headers.get(SEC_WEBSOCKET_ACCEPT).map(|v| v.as_bytes());
+    if accept != Some(accept_key(key.as_bytes()).as_bytes()) {
+        return invalid("invalid websocket accept key");
+    }
+    Ok(())
+}
+
+// check if the comma separated header values contain the token
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter().any(|v| {
+        v.to_str()
+            .map(|s| s.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    })
+}
+
+// check if the request headers ask for a websocket upgrade
+pub(crate) fn is_upgrade_request(version: Version, headers: &HeaderMap) -> bool {
+    version == Version::HTTP_11
+        && has_token(headers, CONNECTION, "upgrade")
+        && has_token(headers, UPGRADE, "websocket")
+}
+
+/// check if the server request is a websocket upgrade request
+pub fn is_upgrade(req: &crate::server::Request) -> bool {
+    is_upgrade_request(req.version(), req.headers())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // the example in RFC 6455
+        let key = accept_key(b"dGhlIHNhbXBsZSBub25jZQ==");
+        assert_eq!(key, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_upgrade_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, "keep-alive, Upgrade".parse().unwrap());
+        headers.insert(UPGRADE, "WebSocket".parse().unwrap());
+        assert!(is_upgrade_request(Version::HTTP_11, &headers));
+        assert!(!is_upgrade_request(Version::HTTP_10, &headers));
+        headers.insert(UPGRADE, "h2c".parse().unwrap());
+        assert!(!is_upgrade_request(Version::HTTP_11, &headers));
+    }
+}
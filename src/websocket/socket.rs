@@ -0,0 +1,493 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+use http::header::*;
+use http::{Method, StatusCode};
+
+use super::frame::{self, OpCode};
+use super::{CloseFrame, Config, Message, ReadWrite};
+use crate::server::{Request, Response};
+use crate::Error;
+
+// close status codes, see RFC 6455 section 7.4.1
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+#[inline]
+fn invalid(msg: &'static str) -> io::Error {
+    Error::Parse(msg.to_owned()).into()
+}
+
+#[inline]
+fn closed() -> io::Error {
+    Error::ConnectionClosed.into()
+}
+
+/// which side of the connection the websocket is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Server,
+    Client,
+}
+
+/// a websocket connection
+///
+/// it's created from the server upgrade request or the client upgrade
+/// handshake and takes over the underlying connection, the connection
+/// is closed after the websocket is dropped
+pub struct WebSocket {
+    // the underline stream
+    stream: Rc<RefCell<dyn ReadWrite>>,
+    role: Role,
+    config: Config,
+    // the fragmented message that is not finished yet
+    partial: Option<(OpCode, Vec<u8>)>,
+    // whether the close frame is sent
+    close_sent: bool,
+    // whether the close frame is received
+    close_received: bool,
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<WebSocket>")
+    }
+}
+
+impl WebSocket {
+    /// accept the websocket upgrade request with default configuration
+    ///
+    /// the `101 Switching Protocols` response is sent out, and the
+    /// connection is taken over by the returned websocket. if the request
+    /// is not a valid upgrade request, the response status is set to
+    /// `400 Bad Request` and an error is returned.
+    ///
+    /// you can set the `Sec-WebSocket-Protocol` header on the response
+    /// before accepting the request to select a sub protocol.
+    pub fn accept(req: Request, rsp: &mut Response) -> io::Result<WebSocket> {
+        Self::accept_with_config(req, rsp, Config::default())
+    }
+
+    /// accept the websocket upgrade request with the given configuration
+    pub fn accept_with_config(
+        mut req: Request,
+        rsp: &mut Response,
+        config: Config,
+    ) -> io::Result<WebSocket> {
+        if req
+            .headers()
+            .get(SEC_WEBSOCKET_VERSION)
+            .map(|v| v.as_bytes())
+            != Some(b"13")
+        {
+            *rsp.status_mut() = StatusCode::UPGRADE_REQUIRED;
+            rsp.headers_mut()
+                .insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+            return Err(invalid("unsupported websocket version"));
+        }
+
+        let key = match req.headers().get(SEC_WEBSOCKET_KEY) {
+            Some(key) if is_valid_key(key.as_bytes()) => key.as_bytes(),
+            _ => {
+                *rsp.status_mut() = StatusCode::BAD_REQUEST;
+                return Err(invalid("invalid websocket key"));
+            }
+        };
+        let accept = super::accept_key(key);
+
+        let stream = match req.take_upgrade() {
+            Some(stream) if req.method() == Method::GET => stream,
+            _ => {
+                *rsp.status_mut() = StatusCode::BAD_REQUEST;
+                return Err(invalid("not a websocket upgrade request"));
+            }
+        };
+
+        *rsp.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        let headers = rsp.headers_mut();
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(SEC_WEBSOCKET_ACCEPT, accept.parse().unwrap());
+        rsp.switch_protocol()?;
+
+        Ok(WebSocket::new(stream, Role::Server, config))
+    }
+
+    // create the websocket on the upgraded connection
+    pub(crate) fn new(stream: Rc<RefCell<dyn ReadWrite>>, role: Role, config: Config) -> Self {
+        WebSocket {
+            stream,
+            role,
+            config,
+            partial: None,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// read a message from the websocket
+    ///
+    /// fragmented messages are assembled, and a pong is replied for each
+    /// ping automatically. after the close message is returned, all the
+    /// successive reads return error.
+    pub fn read_message(&mut self) -> io::Result<Message> {
+        if self.close_received {
+            return Err(closed());
+        }
+
+        loop {
+            let head = {
+                let mut stream = self.stream.borrow_mut();
+                match frame::read_head(&mut *stream) {
+                    Ok(head) => head,
+                    Err(e) => {
+                        drop(stream);
+                        return self.fail_with(e);
+                    }
+                }
+            };
+
+            // the client must mask all the frames, and the server must not
+            match (self.role, head.mask.is_some()) {
+                (Role::Server, false) => {
+                    return self.fail(CLOSE_PROTOCOL_ERROR, "unmasked websocket frame")
+                }
+                (Role::Client, true) => {
+                    return self.fail(CLOSE_PROTOCOL_ERROR, "masked websocket frame")
+                }
+                _ => {}
+            }
+            if head.len > self.config.max_frame_size as u64 {
+                return self.fail(CLOSE_TOO_BIG, "websocket frame too large");
+            }
+
+            let payload = frame::read_payload(&mut *self.stream.borrow_mut(), &head)?;
+
+            match head.opcode {
+                OpCode::Ping => {
+                    if !self.close_sent {
+                        self.send_frame(true, OpCode::Pong, &payload)?;
+                    }
+                    return Ok(Message::Ping(payload));
+                }
+                OpCode::Pong => return Ok(Message::Pong(payload)),
+                OpCode::Close => return self.on_close(&payload),
+                OpCode::Continue => {
+                    let (opcode, mut data) = match self.partial.take() {
+                        Some(partial) => partial,
+                        None => {
+                            return self.fail(CLOSE_PROTOCOL_ERROR, "unexpected continue frame")
+                        }
+                    };
+                    if data.len() + payload.len() > self.config.max_message_size {
+                        return self.fail(CLOSE_TOO_BIG, "websocket message too large");
+                    }
+                    data.extend_from_slice(&payload);
+                    if head.fin {
+                        return self.finish_message(opcode, data);
+                    }
+                    self.partial = Some((opcode, data));
+                }
+                opcode => {
+                    if self.partial.is_some() {
+                        return self.fail(CLOSE_PROTOCOL_ERROR, "unfinished fragmented message");
+                    }
+                    if payload.len() > self.config.max_message_size {
+                        return self.fail(CLOSE_TOO_BIG, "websocket message too large");
+                    }
+                    if head.fin {
+                        return self.finish_message(opcode, payload);
+                    }
+                    self.partial = Some((opcode, payload));
+                }
+            }
+        }
+    }
+
+    /// write a message to the websocket
+    ///
+    /// text and binary messages larger than `max_frame_size` are sent
+    /// in fragments. writing a close message starts the close handshake,
+    /// and successive writes return error.
+    pub fn write_message(&mut self, msg: Message) -> io::Result<()> {
+        if self.close_sent {
+            return Err(closed());
+        }
+
+        match msg {
+            Message::Text(text) => self.send_data(OpCode::Text, text.as_bytes()),
+            Message::Binary(data) => self.send_data(OpCode::Binary, &data),
+            Message::Ping(data) => self.send_control(OpCode::Ping, &data),
+            Message::Pong(data) => self.send_control(OpCode::Pong, &data),
+            Message::Close(frame) => self.send_close(frame),
+        }
+    }
+
+    /// start the close handshake with a normal close status
+    pub fn close(&mut self) -> io::Result<()> {
+        self.write_message(Message::Close(Some(CloseFrame {
+            code: CLOSE_NORMAL,
+            reason: String::new(),
+        })))
+    }
+
+    fn finish_message(&mut self, opcode: OpCode, data: Vec<u8>) -> io::Result<Message> {
+        match opcode {
+            OpCode::Text => match String::from_utf8(data) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => self.fail(CLOSE_INVALID_DATA, "invalid utf-8 text message"),
+            },
+            _ => Ok(Message::Binary(data)),
+        }
+    }
+
+    fn on_close(&mut self, payload: &[u8]) -> io::Result<Message> {
+        let frame = match payload.len() {
+            0 => None,
+            1 => return self.fail(CLOSE_PROTOCOL_ERROR, "invalid close frame"),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                let reason = match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) => reason,
+                    Err(_) => return self.fail(CLOSE_INVALID_DATA, "invalid close reason"),
+                };
+                Some(CloseFrame { code, reason })
+            }
+        };
+        self.close_received = true;
+
+        // reply the close frame
+        if !self.close_sent {
+            let code = frame.as_ref().map(|f| f.code).unwrap_or(CLOSE_NORMAL);
+            self.send_close(Some(CloseFrame {
+                code,
+                reason: String::new(),
+            }))?;
+        }
+        Ok(Message::Close(frame))
+    }
+
+    // send the close frame for the protocol error and return the error
+    fn fail<T>(&mut self, code: u16, msg: &'static str) -> io::Result<T> {
+        self.close_received = true;
+        if !self.close_sent {
+            let frame = CloseFrame {
+                code,
+                reason: msg.to_owned(),
+            };
+            self.send_close(Some(frame)).ok();
+        }
+        Err(invalid(msg))
+    }
+
+    fn fail_with<T>(&mut self, err: io::Error) -> io::Result<T> {
+        if err.kind() == io::ErrorKind::InvalidData {
+            self.close_received = true;
+            if !self.close_sent {
+                let frame = CloseFrame {
+                    code: CLOSE_PROTOCOL_ERROR,
+                    reason: String::new(),
+                };
+                self.send_close(Some(frame)).ok();
+            }
+        }
+        Err(err)
+    }
+
+    fn send_data(&mut self, opcode: OpCode, data: &[u8]) -> io::Result<()> {
+        let max = self.config.max_frame_size.max(1);
+        if data.len() <= max {
+            return self.send_frame(true, opcode, data);
+        }
+
+        let mut chunks = data.chunks(max).peekable();
+        let mut opcode = opcode;
+        while let Some(chunk) = chunks.next() {
+            let fin = chunks.peek().is_none();
+            self.write_frame(fin, opcode, chunk)?;
+            opcode = OpCode::Continue;
+        }
+        self.stream.borrow_mut().flush()
+    }
+
+    fn send_control(&mut self, opcode: OpCode, data: &[u8]) -> io::Result<()> {
+        if data.len() > 125 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "control frame payload too large",
+            ));
+        }
+        self.send_frame(true, opcode, data)
+    }
+
+    fn send_close(&mut self, frame: Option<CloseFrame>) -> io::Result<()> {
+        let mut payload = Vec::new();
+        if let Some(frame) = frame {
+            // the reason must fit into a control frame
+            let mut end = frame.reason.len().min(123);
+            while !frame.reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            payload.extend_from_slice(&frame.code.to_be_bytes());
+            payload.extend_from_slice(&frame.reason.as_bytes()[..end]);
+        }
+        self.close_sent = true;
+        self.send_frame(true, OpCode::Close, &payload)
+    }
+
+    fn send_frame(&mut self, fin: bool, opcode: OpCode, data: &[u8]) -> io::Result<()> {
+        self.write_frame(fin, opcode, data)?;
+        self.stream.borrow_mut().flush()
+    }
+
+    fn write_frame(&mut self, fin: bool, opcode: OpCode, data: &[u8]) -> io::Result<()> {
+        let mask = match self.role {
+            Role::Server => None,
+            Role::Client => Some(super::mask_key()?),
+        };
+        let mut stream = self.stream.borrow_mut();
+        frame::write_frame(&mut *stream, fin, opcode, data, mask)
+    }
+}
+
+// the key must be a base64 encoded 16 bytes value
+fn is_valid_key(key: &[u8]) -> bool {
+    match base64::decode(key) {
+        Ok(v) => v.len() == 16,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::HttpServer;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn echo(req: Request, rsp: &mut Response) {
+        let mut ws = match WebSocket::accept(req, rsp) {
+            Ok(ws) => ws,
+            Err(_) => return,
+        };
+        while let Ok(msg) = ws.read_message() {
+            match msg {
+                Message::Text(_) | Message::Binary(_) => ws.write_message(msg).unwrap(),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn websocket_echo() {
+        let server = HttpServer::new(echo).start("127.0.0.1:8090").unwrap();
+        let mut s = TcpStream::connect("127.0.0.1:8090").unwrap();
+        s.write_all(
+            b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+
+        let mut head = Vec::new();
+        let mut b = [0u8];
+        while !head.ends_with(b"\r\n\r\n") {
+            s.read_exact(&mut b).unwrap();
+            head.push(b[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("sec-websocket-accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        // a fragmented masked text message
+        let mask = [1, 2, 3, 4];
+        frame::write_frame(&mut s, false, OpCode::Text, b"Hel", Some(mask)).unwrap();
+        frame::write_frame(&mut s, true, OpCode::Ping, b"p", Some(mask)).unwrap();
+        frame::write_frame(&mut s, true, OpCode::Continue, b"lo", Some(mask)).unwrap();
+
+        let head = frame::read_head(&mut s).unwrap();
+        assert_eq!(head.opcode, OpCode::Pong);
+        assert_eq!(frame::read_payload(&mut s, &head).unwrap(), b"p");
+        let head = frame::read_head(&mut s).unwrap();
+        assert_eq!(head.opcode, OpCode::Text);
+        assert!(head.mask.is_none());
+        assert_eq!(frame::read_payload(&mut s, &head).unwrap(), b"Hello");
+
+        // the close handshake
+        let close = 1000u16.to_be_bytes();
+        frame::write_frame(&mut s, true, OpCode::Close, &close, Some(mask)).unwrap();
+        let head = frame::read_head(&mut s).unwrap();
+        assert_eq!(head.opcode, OpCode::Close);
+        assert_eq!(frame::read_payload(&mut s, &head).unwrap(), close);
+
+        // unmasked frames are rejected
+        let mut s = TcpStream::connect("127.0.0.1:8090").unwrap();
+        s.write_all(
+            b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            s.read_exact(&mut b).unwrap();
+            head.push(b[0]);
+        }
+        frame::write_frame(&mut s, true, OpCode::Text, b"Hello", None).unwrap();
+        let head = frame::read_head(&mut s).unwrap();
+        assert_eq!(head.opcode, OpCode::Close);
+        let payload = frame::read_payload(&mut s, &head).unwrap();
+        assert_eq!(payload[..2], CLOSE_PROTOCOL_ERROR.to_be_bytes());
+
+        unsafe { server.coroutine().cancel() };
+        server.join().ok();
+    }
+
+    #[test]
+    fn websocket_client() {
+        use crate::client::HttpClient;
+
+        let server = HttpServer::new(echo).start("127.0.0.1:8091").unwrap();
+        let client = HttpClient::connect("127.0.0.1:8091").unwrap();
+        let uri = "ws://127.0.0.1:8091/chat".parse().unwrap();
+        let mut ws = client.websocket(uri).unwrap();
+
+        let msg = Message::Text("Hello WebSocket".to_owned());
+        ws.write_message(msg.clone()).unwrap();
+        ws.write_message(Message::Ping(b"ping".to_vec())).unwrap();
+        assert_eq!(ws.read_message().unwrap(), msg);
+        assert_eq!(ws.read_message().unwrap(), Message::Pong(b"ping".to_vec()));
+        let msg = Message::Binary(vec![1, 2, 3]);
+        ws.write_message(msg.clone()).unwrap();
+        assert_eq!(ws.read_message().unwrap(), msg);
+
+        ws.close().unwrap();
+        match ws.read_message().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CLOSE_NORMAL),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert!(ws.read_message().is_err());
+        assert!(ws
+            .write_message(Message::Text("closed".to_owned()))
+            .is_err());
+
+        // the plain http server can't be upgraded
+        let server2 = HttpServer::new(|_req: Request, rsp: &mut Response| {
+            rsp.send(b"no websocket").unwrap();
+        })
+        .start("127.0.0.1:8092")
+        .unwrap();
+        let client = HttpClient::connect("127.0.0.1:8092").unwrap();
+        let uri = "ws://127.0.0.1:8092/chat".parse().unwrap();
+        assert!(client.websocket(uri).is_err());
+
+        unsafe { server.coroutine().cancel() };
+        server.join().ok();
+        unsafe { server2.coroutine().cancel() };
+        server2.join().ok();
+    }
+}